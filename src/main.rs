@@ -1,226 +1,435 @@
-use evdev::InputEventKind;
-use serde::Deserialize;
-use serde_yaml;
-use std::env;
+use evdev::Device;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
 use std::error::Error;
 use std::fs;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{thread, time};
-use swayipc::{Connection, EventType};
-use uinput;
-use uinput_sys::EV_KEY;
+use swayipc::{reply, Connection, EventStream, EventType};
 
 mod utils;
 
-use utils::config_parser::ConfigKeyCombination;
-use utils::input;
-use utils::keycodes;
-use utils::wayland;
+use utils::cli::{self, Command};
+use utils::config_parser::{self, Config, Setting};
+use utils::exec::Launcher;
+use utils::input::{self, DeviceChange, DeviceWatcher};
+use utils::keyboard::Keyboard;
+use utils::macros::Macros;
+use utils::output::VirtualKeyboard;
+use utils::text::ActiveLayout;
+use utils::wayland::{self, Focus, SwayCommands};
 
-#[derive(Debug, PartialEq, Deserialize, Clone)]
-struct Setting {
-    applications: Vec<String>,
-    remap: Vec<RemapSetting>,
-}
+// How long the keyboard thread blocks on the devices before checking whether
+// it should shut down.
+const POLL_TIMEOUT_MS: i32 = 100;
 
-#[derive(Debug, PartialEq, Deserialize, Clone)]
-struct RemapSetting {
-    from: ConfigKeyCombination,
-    to: Vec<ConfigKeyCombination>,
-}
+// Errors in a row from the sway event stream before we take it as gone.
+const MAX_EVENT_ERRORS: u32 = 5;
+
+// Bounds of the wait between attempts to reconnect to sway, doubling from
+// the first to the second.
+const RECONNECT_DELAY_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(30);
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = env::args().collect();
-    let config_str =
-        fs::read_to_string(&args[1]).expect("Something went wrong reading the config file");
-    let settings: Vec<Setting> =
-        serde_yaml::from_str(&config_str).expect("Unable to read config file");
+    let args = cli::parse_args()?;
+    if args.command == Command::ListDevices {
+        input::list_devices();
+        return Ok(());
+    }
+
+    let config_str = fs::read_to_string(&args.config_path)
+        .expect("Something went wrong reading the config file");
+    let mut config = config_parser::parse_config(&config_str).expect("Unable to read config file");
+    let settings = config.settings.clone();
 
     println!("[settings] {:?}", settings);
 
-    let remap_enabled = Arc::new(Mutex::new(false));
-    let mut handles = vec![];
+    // Devices given on the command line replace the config's choice; ignored
+    // devices add up.
+    if !args.devices.is_empty() {
+        config.devices.only = args.devices;
+    }
+    config.devices.ignore.extend(args.ignore_devices);
+
+    // Settings whose `applications` match the focused window. Replaced by the
+    // sway thread on focus changes; the keyboard thread only clones the Arc.
+    let active_settings: Arc<Mutex<Arc<Vec<Setting>>>> = Arc::new(Mutex::new(Arc::new(vec![])));
+
+    // Set by a signal. The keyboard thread then releases everything it holds
+    // and ungrabs the devices.
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM, SIGHUP] {
+        signal_hook::flag::register(signal, Arc::clone(&shutdown))?;
+    }
+
+    // Stands in for the focused window while sway is gone.
+    let fallback = config
+        .default_profile
+        .clone()
+        .map_or(Focus::Nothing, |class| Focus::Window {
+            class,
+            title: String::new(),
+        });
+    let layout = ActiveLayout::default();
+    let (sway_socket, stream, mut conn) = connect(&args.sway_socket, &args.sway_user)?;
+    println!("[main] Using sway socket {}", sway_socket);
+    // Where sway currently listens, for `exec` and `sway` steps of macros.
+    let sway_socket = Arc::new(Mutex::new(sway_socket));
+    let launcher = Launcher::new(Arc::clone(&sway_socket))?;
+    let sway_commands = SwayCommands::new(Arc::clone(&sway_socket));
 
-    // TODO: get sway socket programatically!
-    let conn = Connection::new(Some("/run/user/1000/sway-ipc.1000.25887.sock".to_string()))?;
+    // Start with the focused window's settings, rather than none until the
+    // focus first changes. Subscribed first, so no change slips in between.
+    sync_focus(&mut conn, &fallback, &settings, &active_settings);
+    sync_layout(&mut conn, &layout);
 
     /////////////////////////
     // Sway subscribe part
     /////////////////////////
-    let remap_enabled_cloned = Arc::clone(&remap_enabled);
+    let active_settings_cloned = Arc::clone(&active_settings);
     let settings_1 = settings.clone();
-    handles.push(thread::spawn(move || {
-        let mut stream = conn
-            .subscribe(&[EventType::Window])
-            .expect("Unable to subscribe sway");
+    let layout_1 = layout.clone();
+    thread::spawn(move || {
+        let mut stream = stream;
         loop {
-            let window_class = wayland::get_window_class(stream.next());
-            let mut remap_enabled_lock = remap_enabled_cloned.lock().unwrap();
-
-            if settings_1
-                .iter()
-                .any(|setting| setting.applications.iter().any(|app| app == &window_class))
-            {
-                println!("[subscribe] Remap enabled for {}", window_class);
-                *remap_enabled_lock = true;
-            } else {
-                println!("[subscribe] Remap disabled for {}", window_class);
-                *remap_enabled_lock = false;
-            }
+            follow_focus(stream, &settings_1, &active_settings_cloned, &layout_1);
+            activate_settings(&fallback, &settings_1, &active_settings_cloned);
+            let (new_stream, mut conn) =
+                reconnect(&args.sway_socket, &args.sway_user, &sway_socket);
+            sync_focus(&mut conn, &fallback, &settings_1, &active_settings_cloned);
+            sync_layout(&mut conn, &layout_1);
+            stream = new_stream;
         }
-    }));
+    });
 
     /////////////////////////
     // Keyboard part
     /////////////////////////
-    let mut device = input::get_keyboard_device().expect("Failed to get keyboard device");
+    let devices = input::get_keyboard_devices(&config.devices);
+    if devices.is_empty() {
+        println!("[main] No keyboard found yet. Waiting for one to be plugged in.");
+    }
+    let mut watcher = DeviceWatcher::new()?;
 
-    let mut virtual_input = uinput::default()
-        .expect("Please load uinput module; Possibly you should run `modprobe uinput`")
-        .name("/dev/uinput")?
-        .event(uinput::event::Keyboard::All)?
-        .event(uinput::event::Controller::All)?
-        .create()?;
+    let sources: Vec<&Device> = devices.iter().map(|(_, device)| device).collect();
+    // Shared with the thread playing macros.
+    let virtual_input = Arc::new(Mutex::new(VirtualKeyboard::new(&sources)?));
+    let macros = Macros::spawn(Arc::clone(&virtual_input), launcher, sway_commands);
+    let mut keyboards: Vec<Keyboard> = devices
+        .into_iter()
+        .map(|(path, device)| Keyboard::new(path, device, &config, &macros, &layout))
+        .collect();
 
     // To wait virtual_input is ready (important)
     thread::sleep(time::Duration::from_secs(1));
 
     // Intercept real input
-    device.grab()?;
-
-    let mut is_caps_pressing = false;
-    let mut is_alt_pressing = false;
-    let mut is_shift_pressing = false;
-
-    let remap_enabled_cloned_2 = Arc::clone(&remap_enabled);
-    let settings_2 = settings.clone();
-    handles.push(thread::spawn(move || loop {
-        let remap_enabled_ = *remap_enabled_cloned_2.lock().unwrap();
-        let events = device.fetch_events().unwrap();
-        events.for_each(|event| {
-            match event.kind() {
-                InputEventKind::Key(orig_key) => {
+    for keyboard in keyboards.iter_mut() {
+        println!(
+            "[main] Grabbing {} ({})",
+            keyboard.path.display(),
+            keyboard.device.name().unwrap_or("unknown")
+        );
+        keyboard.device.grab()?;
+    }
+
+    let active_settings_cloned_2 = Arc::clone(&active_settings);
+    let shutdown_2 = Arc::clone(&shutdown);
+    let keyboard_handle = thread::spawn(move || {
+        while !shutdown_2.load(Ordering::SeqCst) {
+            let mut fds: Vec<RawFd> = keyboards
+                .iter()
+                .map(|keyboard| keyboard.device.as_raw_fd())
+                .collect();
+            fds.push(watcher.as_raw_fd());
+            // Wake up early if a keyboard has a timer running out, e.g. a
+            // dual-role key turning into a hold.
+            let timeout_ms = keyboards
+                .iter()
+                .filter_map(|keyboard| keyboard.next_deadline())
+                .min()
+                .map_or(POLL_TIMEOUT_MS, |deadline| {
+                    let wait = deadline.saturating_duration_since(Instant::now());
+                    POLL_TIMEOUT_MS.min(wait.as_millis() as i32)
+                });
+            let ready = input::wait_readable(&fds, timeout_ms);
+            // Read after the blocking poll, so a focus change made while we
+            // were waiting applies to these events.
+            let settings_2 = Arc::clone(&active_settings_cloned_2.lock().unwrap());
+            let mut virtual_input = virtual_input.lock().unwrap();
+
+            // Device changes are applied after the keyboards are read, as
+            // they add and remove keyboards, which would shift the indices.
+            let watcher_index = keyboards.len();
+            let watcher_ready = ready.contains(&watcher_index);
+
+            // Reversed, so removing a keyboard keeps the other indices valid.
+            for i in ready.into_iter().rev().filter(|&i| i != watcher_index) {
+                if let Err(e) = keyboards[i].process_events(&settings_2, &mut virtual_input) {
                     println!(
-                        "[InputEventKind::Key] {:?} -> {:?}",
-                        orig_key,
-                        event.value()
+                        "[keyboard] Failed to read {}: {}",
+                        keyboards[i].path.display(),
+                        e
                     );
+                    remove_keyboard(
+                        &keyboards[i].path.clone(),
+                        &mut keyboards,
+                        &mut virtual_input,
+                    );
+                }
+            }
 
-                    if !remap_enabled_ {
-                        virtual_input
-                            .write(EV_KEY, orig_key.code() as i32, event.value())
-                            .unwrap();
-                        return;
-                    }
-
-                    // capture ctrl and meta key
-                    match (keycodes::code_to_name(orig_key.code()), event.value()) {
-                        ("capslock", 1) => is_caps_pressing = true,
-                        ("capslock", 0) => is_caps_pressing = false,
-                        ("leftalt", 1) => is_alt_pressing = true,
-                        ("leftalt", 0) => is_alt_pressing = false,
-                        ("rightalt", 1) => is_alt_pressing = true,
-                        ("rightalt", 0) => is_alt_pressing = false,
-                        (_, _) => {}
-                    }
-
-                    let mut handled = false;
-                    settings_2.iter().for_each(|setting| {
-                        setting.remap.iter().for_each(|remap| {
-                            if handled {
-                                return;
-                            }
-                            let should_handle = match (
-                                is_caps_pressing,
-                                remap.from.is_ctrl,
-                                is_alt_pressing,
-                                remap.from.is_alt,
-                                remap.from.keyname == keycodes::code_to_name(orig_key.code()),
-                            ) {
-                                (_, _, _, _, false) => false,
-                                (true, true, false, false, true) => true,
-                                (false, false, true, true, true) => true,
-                                (true, true, true, true, true) => true,
-                                (_, _, _, _, _) => false,
-                            };
-                            if should_handle {
-                                handled = true;
-                                remap.to.iter().for_each(|to| {
-                                    println!("[remap.to] {}", to.value);
-                                    if is_caps_pressing && !to.is_ctrl {
-                                        virtual_input
-                                            .write(EV_KEY, keycodes::name_to_code("capslock"), 0)
-                                            .unwrap();
-                                    }
-                                    if is_alt_pressing && !to.is_alt {
-                                        virtual_input
-                                            .write(EV_KEY, keycodes::name_to_code("leftalt"), 0)
-                                            .unwrap();
-                                    }
-                                    if to.is_ctrl {
-                                        virtual_input
-                                            .write(EV_KEY, keycodes::name_to_code("capslock"), 1)
-                                            .unwrap();
-                                    }
-                                    if to.is_shift {
-                                        virtual_input
-                                            .write(EV_KEY, keycodes::name_to_code("leftshift"), 1)
-                                            .unwrap();
-                                    }
-                                    virtual_input
-                                        .write(
-                                            EV_KEY,
-                                            keycodes::name_to_code(&to.keyname),
-                                            event.value(),
-                                        )
-                                        .unwrap();
-                                    if to.is_shift {
-                                        virtual_input
-                                            .write(EV_KEY, keycodes::name_to_code("leftshift"), 0)
-                                            .unwrap();
-                                    }
-                                    if to.is_ctrl {
-                                        virtual_input
-                                            .write(EV_KEY, keycodes::name_to_code("capslock"), 0)
-                                            .unwrap();
-                                    }
-                                });
-                            }
-                        });
-                    });
-
-                    if !handled {
-                        if is_caps_pressing {
-                            virtual_input
-                                .write(EV_KEY, keycodes::name_to_code("capslock"), 1)
-                                .unwrap();
-                        }
-                        if is_alt_pressing {
-                            virtual_input
-                                .write(EV_KEY, keycodes::name_to_code("leftalt"), 1)
-                                .unwrap();
-                        }
-                        virtual_input
-                            .write(EV_KEY, orig_key.code() as i32, event.value())
-                            .unwrap();
-                    }
+            if watcher_ready {
+                match watcher.read_changes() {
+                    Ok(changes) => changes.into_iter().for_each(|change| {
+                        handle_device_change(
+                            change,
+                            &config,
+                            &macros,
+                            &layout,
+                            &mut keyboards,
+                            &mut virtual_input,
+                        )
+                    }),
+                    Err(e) => println!("[hotplug] Failed to read /dev/input changes: {}", e),
                 }
-                InputEventKind::Synchronization(_) => {
-                    virtual_input.synchronize().unwrap();
+            }
+
+            for keyboard in keyboards.iter_mut() {
+                if let Err(e) = keyboard.tick(&settings_2, &mut virtual_input) {
+                    println!("[keyboard] Failed to write events: {}", e);
                 }
-                _ => {}
             }
-        });
-    }));
+        }
+
+        println!("[keyboard] Releasing held keys and the keyboards.");
+        if let Err(e) = virtual_input.lock().unwrap().release_all() {
+            println!("[keyboard] Failed to release held keys: {}", e);
+        }
+        for keyboard in keyboards.iter_mut() {
+            if let Err(e) = keyboard.device.ungrab() {
+                println!(
+                    "[keyboard] Failed to ungrab {}: {}",
+                    keyboard.path.display(),
+                    e
+                );
+            }
+        }
+    });
 
     println!("[main] sway-remap is ready.");
 
-    for handle in handles {
-        handle.join().unwrap();
-    }
+    // The sway thread may be blocked on the IPC socket; it goes away with the
+    // process once the keyboard has been cleaned up.
+    keyboard_handle.join().unwrap();
+    println!("[main] Bye.");
 
-    // TODO: add cleanup function
-    // device.ungrab()?;
-    //
     Ok(())
 }
+
+// Subscribes to focus changes on sway's socket, plus a connection to ask for
+// the focus with.
+fn connect(
+    sway_socket: &Option<String>,
+    sway_user: &Option<String>,
+) -> Result<(String, EventStream, Connection), Box<dyn Error>> {
+    let sway_socket = wayland::find_sway_socket(sway_socket.clone(), sway_user.clone())?;
+    let stream = Connection::new(Some(sway_socket.clone()))?.subscribe(&[
+        EventType::Window,
+        EventType::Workspace,
+        EventType::Input,
+        EventType::Shutdown,
+    ])?;
+    let conn = Connection::new(Some(sway_socket.clone()))?;
+    Ok((sway_socket, stream, conn))
+}
+
+// Retries until sway is back. A restarted sway listens on a new socket, so
+// it's looked up each time, and `current_socket` updated.
+fn reconnect(
+    sway_socket: &Option<String>,
+    sway_user: &Option<String>,
+    current_socket: &Mutex<String>,
+) -> (EventStream, Connection) {
+    let mut delay = RECONNECT_DELAY_MIN;
+    loop {
+        thread::sleep(delay);
+        match connect(sway_socket, sway_user) {
+            Ok((new_socket, stream, conn)) => {
+                println!("[subscribe] Reconnected to sway at {}", new_socket);
+                *current_socket.lock().unwrap() = new_socket;
+                return (stream, conn);
+            }
+            Err(e) => println!("[subscribe] Failed to reconnect to sway: {}", e),
+        }
+        delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+    }
+}
+
+// Activates the settings for what has the focus now.
+fn sync_focus(
+    conn: &mut Connection,
+    fallback: &Focus,
+    settings: &[Setting],
+    active_settings: &Mutex<Arc<Vec<Setting>>>,
+) {
+    match wayland::get_focus(conn) {
+        Ok(focus) => activate_settings(&focus, settings, active_settings),
+        Err(e) => {
+            println!("[focus] Failed to get the focused window: {}", e);
+            activate_settings(fallback, settings, active_settings);
+        }
+    }
+}
+
+// Picks up the keyboard layout, for typing `text`.
+fn sync_layout(conn: &mut Connection, layout: &ActiveLayout) {
+    match wayland::get_layout(conn) {
+        Ok(name) => layout.set(name),
+        Err(e) => println!("[text] Failed to get the keyboard layout: {}", e),
+    }
+}
+
+// Follows focus and layout changes until sway shuts down or the connection
+// is lost.
+fn follow_focus(
+    mut stream: EventStream,
+    settings: &[Setting],
+    active_settings: &Mutex<Arc<Vec<Setting>>>,
+    layout: &ActiveLayout,
+) {
+    let mut errors = 0;
+    // Windows are retitled often, e.g. by terminals, mostly to no effect.
+    let mut last_focus = None;
+    loop {
+        let event = match stream.next() {
+            Some(Ok(reply::Event::Shutdown(_))) => {
+                println!("[subscribe] sway is shutting down");
+                return;
+            }
+            Some(Ok(event)) => event,
+            // A single bad event is skipped, but a closed socket fails every
+            // read.
+            Some(Err(e)) if errors < MAX_EVENT_ERRORS => {
+                errors += 1;
+                println!("[subscribe] Failed to read an event: {}", e);
+                continue;
+            }
+            _ => {
+                println!("[subscribe] Lost connection to sway");
+                return;
+            }
+        };
+        errors = 0;
+        if let reply::Event::Input(input) = &event {
+            if let Some(name) = wayland::get_layout_change(input) {
+                layout.set(name);
+            }
+            continue;
+        }
+        match wayland::get_focus_change(event) {
+            Ok(Some(focus)) if last_focus.as_ref() != Some(&focus) => {
+                activate_settings(&focus, settings, active_settings);
+                last_focus = Some(focus);
+            }
+            Ok(_) => {}
+            Err(e) => println!("[subscribe] {}", e),
+        }
+    }
+}
+
+// Make the settings matching the focused window active.
+fn activate_settings(
+    focus: &Focus,
+    settings: &[Setting],
+    active_settings: &Mutex<Arc<Vec<Setting>>>,
+) {
+    let (class, title) = match focus {
+        Focus::Window { class, title } => (class, title),
+        Focus::Nothing => {
+            println!("[focus] Remap disabled, no window has the focus");
+            *active_settings.lock().unwrap() = Arc::new(vec![]);
+            return;
+        }
+    };
+    let matched = config_parser::matching(settings, class, title);
+    if matched.is_empty() {
+        println!("[focus] Remap disabled for {} ({:?})", class, title);
+    } else {
+        println!(
+            "[focus] Remap enabled for {} ({:?}, {} setting(s))",
+            class,
+            title,
+            matched.len()
+        );
+    }
+    *active_settings.lock().unwrap() = Arc::new(matched);
+}
+
+fn handle_device_change(
+    change: DeviceChange,
+    config: &Config,
+    macros: &Macros,
+    layout: &ActiveLayout,
+    keyboards: &mut Vec<Keyboard>,
+    virtual_input: &mut VirtualKeyboard,
+) {
+    let path = match change {
+        DeviceChange::Added(path) => path,
+        DeviceChange::Removed(path) => return remove_keyboard(&path, keyboards, virtual_input),
+    };
+    if keyboards.iter().any(|keyboard| keyboard.path == path) {
+        return;
+    }
+    let mut device = match input::open_keyboard(&path, &config.devices) {
+        Some(device) => device,
+        None => return,
+    };
+    if !virtual_input.covers(&device) {
+        println!(
+            "[hotplug] Recreating the virtual keyboard for {}",
+            path.display()
+        );
+        let mut sources: Vec<&Device> = keyboards.iter().map(|keyboard| &keyboard.device).collect();
+        sources.push(&device);
+        if let Err(e) = virtual_input.release_all() {
+            println!("[hotplug] Failed to release held keys: {}", e);
+        }
+        match VirtualKeyboard::new(&sources) {
+            Ok(new_virtual_input) => *virtual_input = new_virtual_input,
+            Err(e) => println!("[hotplug] Failed to recreate the virtual keyboard: {}", e),
+        }
+    }
+    if let Err(e) = device.grab() {
+        println!("[hotplug] Failed to grab {}: {}", path.display(), e);
+        return;
+    }
+    println!(
+        "[hotplug] Grabbing {} ({})",
+        path.display(),
+        device.name().unwrap_or("unknown")
+    );
+    keyboards.push(Keyboard::new(path, device, config, macros, layout));
+}
+
+fn remove_keyboard(
+    path: &Path,
+    keyboards: &mut Vec<Keyboard>,
+    virtual_input: &mut VirtualKeyboard,
+) {
+    let count = keyboards.len();
+    keyboards.retain(|keyboard| keyboard.path != path);
+    if keyboards.len() == count {
+        return;
+    }
+    println!("[hotplug] Dropping {}", path.display());
+    // Keys held on the vanished keyboard would never see their release. We
+    // don't track which output key came from which keyboard, so release all.
+    if let Err(e) = virtual_input.release_all() {
+        println!("[hotplug] Failed to release held keys: {}", e);
+    }
+}
@@ -0,0 +1,63 @@
+use std::env;
+use std::error::Error;
+
+use crate::utils::config_parser::DeviceSelector;
+
+const USAGE: &str = "Usage: sway-remap [--sway-socket PATH] [--sway-user USER]
+                  [--device SELECTOR]... [--ignore-device SELECTOR]... CONFIG
+       sway-remap list-devices
+
+SELECTOR is a device path (/dev/input/event3, /dev/input/by-id/...),
+a vendor:product ID in hex (046d:c52b), or a name substring (name:Keychron).";
+
+#[derive(Debug, Default, PartialEq)]
+pub enum Command {
+    #[default]
+    Run,
+    ListDevices,
+}
+
+#[derive(Debug, Default)]
+pub struct Args {
+    pub command: Command,
+    pub config_path: String,
+    pub sway_socket: Option<String>,
+    pub sway_user: Option<String>,
+    pub devices: Vec<DeviceSelector>,
+    pub ignore_devices: Vec<DeviceSelector>,
+}
+
+pub fn parse_args() -> Result<Args, Box<dyn Error>> {
+    let mut args = Args::default();
+    let mut config_path = None;
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--sway-socket" => args.sway_socket = Some(expect_value(&arg, iter.next())?),
+            "--sway-user" => args.sway_user = Some(expect_value(&arg, iter.next())?),
+            "--device" => args
+                .devices
+                .push(DeviceSelector::parse(&expect_value(&arg, iter.next())?)),
+            "--ignore-device" => args
+                .ignore_devices
+                .push(DeviceSelector::parse(&expect_value(&arg, iter.next())?)),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ if arg.starts_with("--") => {
+                return Err(format!("Unknown option {}\n{}", arg, USAGE).into())
+            }
+            "list-devices" if config_path.is_none() => args.command = Command::ListDevices,
+            _ => config_path = Some(arg),
+        }
+    }
+    if args.command == Command::Run {
+        args.config_path = config_path.ok_or(USAGE)?;
+    }
+    Ok(args)
+}
+
+fn expect_value(option: &str, value: Option<String>) -> Result<String, Box<dyn Error>> {
+    value.ok_or_else(|| format!("{} requires a value\n{}", option, USAGE).into())
+}
@@ -1,34 +1,858 @@
-use serde::{Deserialize, Deserializer};
+use regex::{Regex, RegexBuilder};
+use serde::{de, Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::utils::keycodes;
+use crate::utils::modifiers::{self, Modifiers};
+use crate::utils::sequence::SequenceTrie;
+
+// Of dual-role and layer keys without a `tapping_term`
+pub const DEFAULT_TAPPING_TERM: Duration = Duration::from_millis(200);
+
+// Most a `key` step can be repeated, as each repeat is queued up front
+const MAX_TIMES: usize = 1000;
+
+#[derive(Debug, PartialEq, Deserialize, Clone, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub devices: DeviceSelection,
+    // Defaults for every `dual_role` key
+    pub tapping_term: Option<ConfigDuration>,
+    #[serde(default)]
+    pub interrupt: Interrupt,
+    #[serde(default)]
+    pub dual_role: Vec<DualRoleSetting>,
+    // How long to wait for the next key of a multi-stroke `from`
+    pub sequence_timeout: Option<ConfigDuration>,
+    #[serde(default)]
+    pub sequence_fallback: SequenceFallback,
+    #[serde(default)]
+    pub layers: Vec<LayerSetting>,
+    // Default for `preserve_modifiers` of every remap
+    #[serde(default)]
+    pub preserve_modifiers: bool,
+    // Application whose settings apply while sway can't be reached
+    pub default_profile: Option<String>,
+    pub settings: Vec<Setting>,
+}
+
+// - name: nav
+//   key: space
+//   activation: [momentary, toggle, lock]
+//   remap:
+//     - from: capslock.h
+//       to: [left]
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+pub struct LayerSetting {
+    pub name: String,
+    pub key: String,
+    #[serde(default = "default_activation")]
+    pub activation: Vec<LayerActivation>,
+    pub tapping_term: Option<ConfigDuration>,
+    #[serde(default)]
+    pub remap: RemapTable,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerActivation {
+    // Active while the layer key is held
+    Momentary,
+    // A tap turns it on, the next tap off
+    Toggle,
+    // A double tap turns it on until the next double tap
+    Lock,
+}
+
+fn default_activation() -> Vec<LayerActivation> {
+    vec![LayerActivation::Momentary]
+}
+
+// - key: capslock
+//   tap: esc
+//   hold: capslock
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+pub struct DualRoleSetting {
+    #[serde(deserialize_with = "deserialize_key_name")]
+    pub key: String,
+    #[serde(deserialize_with = "deserialize_key_name")]
+    pub tap: String,
+    #[serde(deserialize_with = "deserialize_key_name")]
+    pub hold: String,
+    pub tapping_term: Option<ConfigDuration>,
+    pub interrupt: Option<Interrupt>,
+}
+
+// What another key pressed while a dual-role key is undecided does to it.
+#[derive(Debug, PartialEq, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Interrupt {
+    // Any other key press makes it a hold.
+    #[default]
+    HoldOnOtherKeyPress,
+    // Another key pressed and released before it makes it a hold.
+    PermissiveHold,
+    // Only the tapping term decides.
+    TapPreferred,
+}
+
+// What happens to the keys of a sequence that doesn't complete.
+#[derive(Debug, PartialEq, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SequenceFallback {
+    // Send them as if they were never part of a sequence.
+    #[default]
+    Replay,
+    Drop,
+}
+
+// What holding down the key of a remap with a multi-step `to` does.
+#[derive(Debug, PartialEq, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Repeat {
+    // Type out `to` once, on press.
+    #[default]
+    Ignore,
+    // Type out `to` again on every autorepeat.
+    Replay,
+}
+
+// How to type a character of `text` that isn't on the keyboard.
+#[derive(Debug, PartialEq, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TextFallback {
+    // ctrl+shift+u, then the code point in hex and space. Works in GTK and
+    // Qt applications, and wherever IBus is running.
+    #[default]
+    CtrlShiftU,
+    // Leave it out.
+    Skip,
+}
+
+// `200` (milliseconds), `200ms` or `1s`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ConfigDuration(pub Duration);
+
+// Which input devices to grab. With an empty `only`, every keyboard is
+// grabbed. `ignore` always wins.
+#[derive(Debug, PartialEq, Deserialize, Clone, Default)]
+pub struct DeviceSelection {
+    #[serde(default)]
+    pub only: Vec<DeviceSelector>,
+    #[serde(default)]
+    pub ignore: Vec<DeviceSelector>,
+}
+
+// /dev/input/event3, /dev/input/by-id/... => Path
+// 046d:c52b => Id (vendor:product, hex)
+// name:Keychron, Keychron => Name (substring)
+#[derive(Debug, PartialEq, Clone)]
+pub enum DeviceSelector {
+    Path(PathBuf),
+    Id(u16, u16),
+    Name(String),
+}
+
+// An entry of `applications` or `titles`:
+// Brave-browser => the app_id, class or title, exactly
+// chromium* => glob (`*` and `?`), matching the whole name
+// /^firefox/ => regex, matching anywhere in the name
+// ~slack => any of them, ignoring case
+#[derive(Debug, Clone)]
+pub struct NameMatcher {
+    pattern: String,
+    regex: Regex,
+}
+
+// `*` => every application
+// [Brave-browser, chromium*] => these
+#[derive(Debug, PartialEq, Clone)]
+pub enum Applications {
+    All,
+    Only(Vec<NameMatcher>),
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+pub struct Setting {
+    pub applications: Applications,
+    // Applications left out, e.g. terminals from `*`
+    #[serde(default)]
+    pub applications_not: Vec<NameMatcher>,
+    // If given, the window's title has to match one of them
+    #[serde(default)]
+    pub titles: Vec<NameMatcher>,
+    // The window's title must match none of them
+    #[serde(default)]
+    pub titles_not: Vec<NameMatcher>,
+    pub remap: RemapTable,
+    // Remaps of the named layers for these applications. They take priority
+    // over the layer's own `remap`.
+    #[serde(default)]
+    pub layers: HashMap<String, RemapTable>,
+    // Pause between the keys of a multi-step `to`, for applications that
+    // drop keys typed too fast
+    pub key_delay: Option<ConfigDuration>,
+    // How `text` types characters that have no key
+    #[serde(default)]
+    pub text_fallback: TextFallback,
+}
+
+// A list of remaps, with the trie to look them up by `from`.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct RemapTable {
+    pub rules: Vec<RemapSetting>,
+    pub trie: SequenceTrie,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+pub struct RemapSetting {
+    // One combination, or several separated by spaces for a multi-stroke
+    // sequence, e.g. `capslock.x capslock.s`
+    #[serde(deserialize_with = "deserialize_sequence")]
+    pub from: Vec<ConfigKeyCombination>,
+    pub to: Vec<Action>,
+    // Carry held modifiers `from` doesn't use through to `to`, e.g. shift to
+    // select while moving. Defaults to the top-level option.
+    pub preserve_modifiers: Option<bool>,
+    // Only for a multi-step `to`; a single key repeats as usual.
+    #[serde(default)]
+    pub repeat: Repeat,
+}
+
+// One step of `to`
+#[derive(Debug, PartialEq, Clone)]
+pub enum Action {
+    // `capslock.x`: pressed and released, with its modifiers
+    Key(ConfigKeyCombination),
+    // `press: leftshift`: pressed and left down
+    Press(String),
+    // `release: leftshift`
+    Release(String),
+    // `sleep: 20ms`
+    Sleep(Duration),
+    // `{ key: down, times: 5 }`
+    Repeat(ConfigKeyCombination, usize),
+    // `text: "→ café"`
+    Text(String),
+    // `exec: grim`: a shell command, run as the sway user
+    Exec(String),
+    // `sway: focus left`: a sway command
+    Sway(String),
+}
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct ConfigKeyCombination {
-    pub is_alt: bool,
-    pub is_ctrl: bool,
-    pub is_shift: bool,
+    // Any key of each entry has to be held, see `modifiers::parse_modifier`
+    pub modifiers: Vec<Modifiers>,
+    // Modifiers that may be held on top, from wildcards like `[shift?]`
+    pub optional: Modifiers,
     pub keyname: String,
     pub value: String,
 }
 
+impl RemapTable {
+    // Settle each rule's `preserve_modifiers`. A rule that preserves them
+    // matches with other modifiers held too, see `SequenceTrie`.
+    fn preserve_modifiers(&mut self, default: bool) {
+        for rule in self.rules.iter_mut() {
+            rule.preserve_modifiers.get_or_insert(default);
+        }
+        self.trie = SequenceTrie::build(&self.rules);
+    }
+}
+
+impl<'de> Deserialize<'de> for RemapTable {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let rules: Vec<RemapSetting> = Deserialize::deserialize(deserializer)?;
+        Ok(RemapTable {
+            trie: SequenceTrie::build(&rules),
+            rules,
+        })
+    }
+}
+
 impl<'de> Deserialize<'de> for ConfigKeyCombination {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
         let val: String = Deserialize::deserialize(deserializer)?;
+        ConfigKeyCombination::parse(&val).map_err(de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Action {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Step {
+            press: Option<String>,
+            release: Option<String>,
+            sleep: Option<ConfigDuration>,
+            key: Option<String>,
+            times: Option<usize>,
+            text: Option<String>,
+            exec: Option<String>,
+            sway: Option<String>,
+        }
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Key(String),
+            Step(Step),
+        }
+        let step = match Raw::deserialize(deserializer)? {
+            Raw::Key(val) => {
+                return ConfigKeyCombination::parse(&val)
+                    .map(Action::Key)
+                    .map_err(de::Error::custom)
+            }
+            Raw::Step(step) => step,
+        };
+        let Step {
+            press,
+            release,
+            sleep,
+            key,
+            times,
+            text,
+            exec,
+            sway,
+        } = step;
+        let given = [
+            press.is_some(),
+            release.is_some(),
+            sleep.is_some(),
+            key.is_some(),
+            text.is_some(),
+            exec.is_some(),
+            sway.is_some(),
+        ];
+        if given.iter().filter(|&&given| given).count() != 1 || (times.is_some() && key.is_none()) {
+            return Err(de::Error::custom(
+                "expected a key, or one of `press`, `release`, `sleep`, `text`, `exec`, `sway` or `key` with `times`",
+            ));
+        }
+        if times.is_some_and(|times| times > MAX_TIMES) {
+            return Err(de::Error::custom(format!(
+                "`times` must be at most {}",
+                MAX_TIMES
+            )));
+        }
+        if let Some(key) = press {
+            return check_key_name(&key).map(|_| Action::Press(key));
+        }
+        if let Some(key) = release {
+            return check_key_name(&key).map(|_| Action::Release(key));
+        }
+        if let Some(duration) = sleep {
+            return Ok(Action::Sleep(duration.0));
+        }
+        if let Some(text) = text {
+            return Ok(Action::Text(text));
+        }
+        if let Some(command) = exec {
+            return Ok(Action::Exec(command));
+        }
+        if let Some(command) = sway {
+            return Ok(Action::Sway(command));
+        }
+        ConfigKeyCombination::parse(&key.unwrap())
+            .map(|key| Action::Repeat(key, times.unwrap_or(1)))
+            .map_err(de::Error::custom)
+    }
+}
+
+fn deserialize_sequence<'de, D>(deserializer: D) -> Result<Vec<ConfigKeyCombination>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let val: String = Deserialize::deserialize(deserializer)?;
+    let sequence: Vec<ConfigKeyCombination> = val
+        .split_whitespace()
+        .map(ConfigKeyCombination::parse)
+        .collect::<Result<_, _>>()
+        .map_err(de::Error::custom)?;
+    if sequence.is_empty() {
+        return Err(de::Error::custom("`from` must not be empty"));
+    }
+    Ok(sequence)
+}
+
+impl ConfigKeyCombination {
+    pub fn parse(val: &str) -> Result<ConfigKeyCombination, String> {
         let keys: Vec<&str> = val.split(".").collect();
-        let key_iter = keys.iter();
-        let is_alt = key_iter.clone().any(|&x| x == "leftalt" || x == "rightalt");
-        let is_ctrl = key_iter
-            .clone()
-            .any(|&x| x == "leftctrl" || x == "capslock");
-        let is_shift = key_iter.clone().any(|&x| x == "leftshift");
-        let keyname = keys.last().expect("Failed to get key value");
+        let (keyname, modifier_names) = keys.split_last().expect("Failed to get key value");
+        let mut modifiers = vec![];
+        let mut optional = Modifiers::default();
+        for name in modifier_names {
+            if let Some(keys) = modifiers::parse_wildcard(name) {
+                optional = optional.union(keys);
+                continue;
+            }
+            match modifiers::parse_modifier(name) {
+                Some(keys) => modifiers.extend(keys),
+                None => return Err(format!("`{}` in `{}` is not a modifier", name, val)),
+            }
+        }
+        if keycodes::try_name_to_code(keyname).is_none() {
+            return Err(format!("`{}` in `{}` is not a key", keyname, val));
+        }
         Ok(ConfigKeyCombination {
-            is_alt: is_alt,
-            is_ctrl: is_ctrl,
-            is_shift: is_shift,
+            modifiers,
+            optional,
             keyname: keyname.to_string(),
-            value: val,
+            value: val.to_string(),
+        })
+    }
+}
+
+// Checked at load, as an unknown key would only fail once the rule fires.
+fn check_key_name<E: de::Error>(name: &str) -> Result<(), E> {
+    match keycodes::try_name_to_code(name) {
+        Some(_) => Ok(()),
+        None => Err(E::custom(format!("`{}` is not a key", name))),
+    }
+}
+
+fn deserialize_key_name<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name: String = Deserialize::deserialize(deserializer)?;
+    check_key_name(&name).map(|_| name)
+}
+
+impl DeviceSelector {
+    pub fn parse(val: &str) -> DeviceSelector {
+        if val.starts_with('/') {
+            return DeviceSelector::Path(PathBuf::from(val));
+        }
+        if let Some(name) = val.strip_prefix("name:") {
+            return DeviceSelector::Name(name.to_string());
+        }
+        if let Some((vendor, product)) = val.split_once(':') {
+            if let (Ok(vendor), Ok(product)) = (
+                u16::from_str_radix(vendor, 16),
+                u16::from_str_radix(product, 16),
+            ) {
+                return DeviceSelector::Id(vendor, product);
+            }
+        }
+        DeviceSelector::Name(val.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceSelector {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let val: String = Deserialize::deserialize(deserializer)?;
+        Ok(DeviceSelector::parse(&val))
+    }
+}
+
+impl NameMatcher {
+    pub fn parse(val: &str) -> Result<NameMatcher, String> {
+        let (ignore_case, pattern) = match val.strip_prefix('~') {
+            Some(pattern) => (true, pattern),
+            None => (false, val),
+        };
+        let regex = match pattern.strip_prefix('/').and_then(|p| p.strip_suffix('/')) {
+            Some(regex) => regex.to_string(),
+            None => glob_to_regex(pattern),
+        };
+        let regex = RegexBuilder::new(&regex)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|e| format!("`{}` is not a valid application pattern: {}", val, e))?;
+        Ok(NameMatcher {
+            pattern: val.to_string(),
+            regex,
         })
     }
+
+    pub fn is_match(&self, name: &str) -> bool {
+        self.regex.is_match(name)
+    }
+}
+
+impl Setting {
+    // Whether the setting is for a window of `class` titled `title`.
+    pub fn matches(&self, class: &str, title: &str) -> bool {
+        let included = match &self.applications {
+            Applications::All => true,
+            Applications::Only(apps) => apps.iter().any(|app| app.is_match(class)),
+        };
+        included
+            && !self.applications_not.iter().any(|app| app.is_match(class))
+            && (self.titles.is_empty() || self.titles.iter().any(|t| t.is_match(title)))
+            && !self.titles_not.iter().any(|t| t.is_match(title))
+    }
+}
+
+// The settings for a window of `class` titled `title`. Earlier ones win.
+// Settings for the window's application come before the ones for all of them,
+// and keep the config's order otherwise.
+pub fn matching(settings: &[Setting], class: &str, title: &str) -> Vec<Setting> {
+    let mut matched: Vec<Setting> = settings
+        .iter()
+        .filter(|setting| setting.matches(class, title))
+        .cloned()
+        .collect();
+    matched.sort_by_key(|setting| setting.applications == Applications::All);
+    matched
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+impl<'de> Deserialize<'de> for Applications {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            One(String),
+            List(Vec<String>),
+        }
+        let patterns = match Raw::deserialize(deserializer)? {
+            Raw::One(pattern) => vec![pattern],
+            Raw::List(patterns) => patterns,
+        };
+        if patterns.iter().any(|pattern| pattern == "*") {
+            return Ok(Applications::All);
+        }
+        patterns
+            .iter()
+            .map(|pattern| NameMatcher::parse(pattern).map_err(de::Error::custom))
+            .collect::<Result<_, _>>()
+            .map(Applications::Only)
+    }
+}
+
+// Compiled from the same pattern, so they match the same.
+impl PartialEq for NameMatcher {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+impl<'de> Deserialize<'de> for NameMatcher {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let val: String = Deserialize::deserialize(deserializer)?;
+        NameMatcher::parse(&val).map_err(de::Error::custom)
+    }
+}
+
+// The config is either a bare list of settings, or a map with `settings` and
+// other top-level options.
+pub fn parse_config(config_str: &str) -> Result<Config, serde_yaml::Error> {
+    let value: serde_yaml::Value = serde_yaml::from_str(config_str)?;
+    let mut config: Config = if value.is_sequence() {
+        Config {
+            settings: serde_yaml::from_value(value)?,
+            ..Default::default()
+        }
+    } else {
+        serde_yaml::from_value(value)?
+    };
+    let preserve = config.preserve_modifiers;
+    for setting in config.settings.iter_mut() {
+        setting.remap.preserve_modifiers(preserve);
+        for table in setting.layers.values_mut() {
+            table.preserve_modifiers(preserve);
+        }
+    }
+    for layer in config.layers.iter_mut() {
+        layer.remap.preserve_modifiers(preserve);
+    }
+    for layer in config.layers.iter() {
+        if keycodes::try_name_to_code(&layer.key).is_none() {
+            return Err(de::Error::custom(format!(
+                "`{}` of layer `{}` is not a key",
+                layer.key, layer.name
+            )));
+        }
+    }
+    for setting in config.settings.iter() {
+        for name in setting.layers.keys() {
+            if !config.layers.iter().any(|layer| &layer.name == name) {
+                return Err(de::Error::custom(format!(
+                    "`{}` is not a defined layer",
+                    name
+                )));
+            }
+        }
+    }
+    Ok(config)
+}
+
+impl<'de> Deserialize<'de> for ConfigDuration {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Millis(u64),
+            Text(String),
+        }
+        let millis = match Raw::deserialize(deserializer)? {
+            Raw::Millis(millis) => Some(millis),
+            Raw::Text(val) => match (val.strip_suffix("ms"), val.strip_suffix('s')) {
+                (Some(millis), _) => millis.trim().parse().ok(),
+                (None, Some(secs)) => secs
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+                    .and_then(|secs| secs.checked_mul(1000)),
+                (None, None) => val.trim().parse().ok(),
+            },
+        };
+        millis
+            .map(|millis| ConfigDuration(Duration::from_millis(millis)))
+            .ok_or_else(|| de::Error::custom("expected a duration like 200ms or 1s"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn duration(yaml: &str) -> Result<Duration, serde_yaml::Error> {
+        serde_yaml::from_str::<ConfigDuration>(yaml).map(|duration| duration.0)
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(duration("200").unwrap(), Duration::from_millis(200));
+        assert_eq!(duration("180ms").unwrap(), Duration::from_millis(180));
+        assert_eq!(duration("2s").unwrap(), Duration::from_secs(2));
+        assert_eq!(duration("\"150\"").unwrap(), Duration::from_millis(150));
+        assert!(duration("1.5s").is_err());
+        assert!(duration("fast").is_err());
+        assert!(duration("18446744073709551615s").is_err());
+    }
+
+    #[test]
+    fn key_combinations() {
+        let combination = ConfigKeyCombination::parse("ctrl.[shift?].a").unwrap();
+        assert_eq!(combination.keyname, "a");
+        assert_eq!(
+            combination.modifiers,
+            modifiers::parse_modifier("ctrl").unwrap()
+        );
+        assert_eq!(
+            combination.optional,
+            modifiers::parse_wildcard("[shift?]").unwrap()
+        );
+
+        let combination = ConfigKeyCombination::parse("[any].f1").unwrap();
+        assert!(combination.modifiers.is_empty());
+        assert_eq!(combination.optional, Modifiers::all());
+
+        assert!(ConfigKeyCombination::parse("capslock")
+            .unwrap()
+            .modifiers
+            .is_empty());
+        assert!(ConfigKeyCombination::parse("ctl.a").is_err());
+        assert!(ConfigKeyCombination::parse("ctrl.[shift].a").is_err());
+        assert!(ConfigKeyCombination::parse("ctrl.notakey").is_err());
+    }
+
+    #[test]
+    fn device_selectors() {
+        assert_eq!(
+            DeviceSelector::parse("/dev/input/event3"),
+            DeviceSelector::Path(PathBuf::from("/dev/input/event3"))
+        );
+        assert_eq!(
+            DeviceSelector::parse("046d:C52B"),
+            DeviceSelector::Id(0x046d, 0xc52b)
+        );
+        assert_eq!(
+            DeviceSelector::parse("name:046d:c52b"),
+            DeviceSelector::Name("046d:c52b".to_string())
+        );
+        assert_eq!(
+            DeviceSelector::parse("Keychron K2"),
+            DeviceSelector::Name("Keychron K2".to_string())
+        );
+        assert_eq!(
+            DeviceSelector::parse("Logitech: MX Keys"),
+            DeviceSelector::Name("Logitech: MX Keys".to_string())
+        );
+    }
+
+    fn action(yaml: &str) -> Result<Action, serde_yaml::Error> {
+        serde_yaml::from_str(yaml)
+    }
+
+    #[test]
+    fn actions() {
+        let key = ConfigKeyCombination::parse("ctrl.c").unwrap();
+        assert_eq!(action("ctrl.c").unwrap(), Action::Key(key.clone()));
+        assert_eq!(
+            action("{ key: ctrl.c, times: 3 }").unwrap(),
+            Action::Repeat(key.clone(), 3)
+        );
+        assert_eq!(action("{ key: ctrl.c }").unwrap(), Action::Repeat(key, 1));
+        assert_eq!(
+            action("press: leftshift").unwrap(),
+            Action::Press("leftshift".to_string())
+        );
+        assert_eq!(
+            action("release: leftshift").unwrap(),
+            Action::Release("leftshift".to_string())
+        );
+        assert_eq!(
+            action("sleep: 20ms").unwrap(),
+            Action::Sleep(Duration::from_millis(20))
+        );
+        assert_eq!(
+            action("text: café").unwrap(),
+            Action::Text("café".to_string())
+        );
+        assert_eq!(
+            action("exec: grim").unwrap(),
+            Action::Exec("grim".to_string())
+        );
+        assert_eq!(
+            action("sway: focus left").unwrap(),
+            Action::Sway("focus left".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_dual_role_keys() {
+        let config =
+            |dual_role: &str| parse_config(&format!("dual_role: [{}]\nsettings: []", dual_role));
+        assert!(config("{ key: capslock, tap: esc, hold: leftctrl }").is_ok());
+        assert!(config("{ key: capslok, tap: esc, hold: leftctrl }").is_err());
+        assert!(config("{ key: capslock, tap: escape, hold: leftctrl }").is_err());
+        assert!(config("{ key: capslock, tap: esc, hold: ctrl }").is_err());
+    }
+
+    #[test]
+    fn invalid_layers() {
+        let config = |layers: &str, settings: &str| {
+            parse_config(&format!("layers: [{}]\nsettings: [{}]", layers, settings))
+        };
+        assert!(config("{ name: nav, key: space }", "").is_ok());
+        assert!(config("{ name: nav, key: spcae }", "").is_err());
+        assert!(config(
+            "{ name: nav, key: space }",
+            "{ applications: '*', remap: [], layers: { sym: [] } }"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn invalid_actions() {
+        assert!(action("{ press: a, release: a }").is_err());
+        assert!(action("{ text: a, times: 2 }").is_err());
+        assert!(action("{ times: 2 }").is_err());
+        assert!(action("{ key: down, times: 1000 }").is_ok());
+        assert!(action("{ key: down, times: 1000000000 }").is_err());
+        assert!(action("{ hold: a }").is_err());
+        assert!(action("press: shfit").is_err());
+        assert!(action("release: notakey").is_err());
+        assert!(action("ctrl.notakey").is_err());
+        assert!(action("{ key: ctl.a, times: 2 }").is_err());
+    }
+
+    fn name_matches(pattern: &str, name: &str) -> bool {
+        NameMatcher::parse(pattern).unwrap().is_match(name)
+    }
+
+    #[test]
+    fn name_matchers() {
+        assert!(name_matches("Brave-browser", "Brave-browser"));
+        assert!(!name_matches("Brave-browser", "brave-browser"));
+        assert!(!name_matches("Brave", "Brave-browser"));
+        assert!(name_matches("chromium*", "chromium-browser"));
+        assert!(!name_matches("chromium*", "ungoogled-chromium"));
+        assert!(name_matches("kitty?", "kitty2"));
+        assert!(!name_matches("kitty?", "kitty"));
+        assert!(name_matches("a.b", "a.b"));
+        assert!(!name_matches("a.b", "axb"));
+        assert!(name_matches("/^(firefox|chromium)$/", "chromium"));
+        assert!(name_matches("/vim/", "nvim-qt"));
+        assert!(name_matches("~brave-browser", "Brave-browser"));
+        assert!(name_matches("~/^FIRE/", "firefox"));
+        assert!(NameMatcher::parse("/(/").is_err());
+    }
+
+    #[test]
+    fn setting_matches() {
+        let setting: Setting = serde_yaml::from_str(
+            "
+            applications: '*'
+            applications_not: [kitty, ~/term/]
+            titles_not: ['*- YouTube*']
+            remap: []
+            ",
+        )
+        .unwrap();
+        assert!(setting.matches("firefox", "Mozilla Firefox"));
+        assert!(!setting.matches("kitty", "vim"));
+        assert!(!setting.matches("gnome-Terminal", "bash"));
+        assert!(!setting.matches("firefox", "Music - YouTube - Mozilla Firefox"));
+    }
+
+    #[test]
+    fn specific_settings_first() {
+        let settings: Vec<Setting> = serde_yaml::from_str(
+            "
+            - applications: '*'
+              remap: [{ from: capslock.a, to: [home] }]
+            - applications: [firefox]
+              remap: [{ from: capslock.a, to: [capslock.a] }]
+            - applications: '*'
+              titles: ['*YouTube*']
+              remap: [{ from: capslock.a, to: [end] }]
+            - applications: [fire*]
+              remap: [{ from: capslock.b, to: [left] }]
+            - applications: [kitty]
+              remap: []
+            ",
+        )
+        .unwrap();
+        let order = |class, title| -> Vec<usize> {
+            matching(&settings, class, title)
+                .iter()
+                .map(|matched| settings.iter().position(|s| s == matched).unwrap())
+                .collect()
+        };
+        assert_eq!(order("firefox", "YouTube"), vec![1, 3, 0, 2]);
+        assert_eq!(order("firefox", "Mozilla"), vec![1, 3, 0]);
+        assert_eq!(order("brave", "YouTube"), vec![0, 2]);
+        assert_eq!(order("kitty", "vim"), vec![4, 0]);
+    }
 }
@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::utils::config_parser::{Config, Interrupt, DEFAULT_TAPPING_TERM};
+use crate::utils::keycodes;
+
+// A key that sends `tap` when tapped and acts as `hold` while held.
+#[derive(Debug, Clone)]
+struct DualRoleKey {
+    code: u16,
+    tap: u16,
+    hold: u16,
+    tapping_term: Duration,
+    interrupt: Interrupt,
+}
+
+// A dual-role key that is down but not yet decided. Other key events are
+// held back until it is, so they come out after the tap or the hold.
+struct Pending {
+    key: DualRoleKey,
+    deadline: Instant,
+    buffered: Vec<(u16, i32)>,
+}
+
+// Turns physical key events into logical ones for a single keyboard.
+pub struct DualRoleKeys {
+    keys: Vec<DualRoleKey>,
+    pending: Option<Pending>,
+    // Dual-role keys resolved as hold: physical code => hold code.
+    holding: HashMap<u16, u16>,
+}
+
+impl DualRoleKeys {
+    pub fn new(config: &Config) -> DualRoleKeys {
+        let keys = config
+            .dual_role
+            .iter()
+            .map(|setting| DualRoleKey {
+                code: keycodes::name_to_code(&setting.key) as u16,
+                tap: keycodes::name_to_code(&setting.tap) as u16,
+                hold: keycodes::name_to_code(&setting.hold) as u16,
+                tapping_term: setting
+                    .tapping_term
+                    .or(config.tapping_term)
+                    .map_or(DEFAULT_TAPPING_TERM, |term| term.0),
+                interrupt: setting.interrupt.unwrap_or(config.interrupt),
+            })
+            .collect();
+        DualRoleKeys {
+            keys,
+            pending: None,
+            holding: HashMap::new(),
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|pending| pending.deadline)
+    }
+
+    pub fn process(&mut self, code: u16, value: i32, now: Instant) -> Vec<(u16, i32)> {
+        let pending = match self.pending.as_mut() {
+            Some(pending) => pending,
+            None => return self.process_idle(code, value, now),
+        };
+
+        if code == pending.key.code {
+            return match value {
+                0 if now >= pending.deadline => self.resolve(true, vec![(code, 0)]),
+                0 => self.resolve(false, vec![]),
+                // The key's own autorepeat says nothing until it's decided.
+                _ => vec![],
+            };
+        }
+
+        let was_pressed = pending.buffered.contains(&(code, 1));
+        pending.buffered.push((code, value));
+        match (pending.key.interrupt, value) {
+            (Interrupt::HoldOnOtherKeyPress, 1) => self.resolve(true, vec![]),
+            // Another key was pressed and released within the tapping term.
+            (Interrupt::PermissiveHold, 0) if was_pressed => self.resolve(true, vec![]),
+            (_, _) => vec![],
+        }
+    }
+
+    pub fn tick(&mut self, now: Instant) -> Vec<(u16, i32)> {
+        match &self.pending {
+            Some(pending) if now >= pending.deadline => self.resolve(true, vec![]),
+            _ => vec![],
+        }
+    }
+
+    fn process_idle(&mut self, code: u16, value: i32, now: Instant) -> Vec<(u16, i32)> {
+        if let Some(&hold) = self.holding.get(&code) {
+            if value == 0 {
+                self.holding.remove(&code);
+            }
+            return vec![(hold, value)];
+        }
+        match self.keys.iter().find(|key| key.code == code) {
+            Some(key) if value == 1 => {
+                self.pending = Some(Pending {
+                    key: key.clone(),
+                    deadline: now + key.tapping_term,
+                    buffered: vec![],
+                });
+                vec![]
+            }
+            _ => vec![(code, value)],
+        }
+    }
+
+    // Decide the pending key, then replay what was held back behind it.
+    // `then` are events of the dual-role key itself (its release).
+    fn resolve(&mut self, is_hold: bool, then: Vec<(u16, i32)>) -> Vec<(u16, i32)> {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return vec![],
+        };
+        let key = pending.key;
+        println!(
+            "[dual_role] {} resolved as {}",
+            keycodes::code_to_name(key.code),
+            if is_hold { "hold" } else { "tap" }
+        );
+        let mut out = if is_hold {
+            self.holding.insert(key.code, key.hold);
+            vec![(key.hold, 1)]
+        } else {
+            vec![(key.tap, 1), (key.tap, 0)]
+        };
+        for (code, value) in pending.buffered {
+            out.extend(self.process(code, value, Instant::now()));
+        }
+        for (code, value) in then {
+            out.extend(self.process(code, value, Instant::now()));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::config_parser;
+    use std::time::Duration;
+
+    fn dual_role_keys(interrupt: &str) -> DualRoleKeys {
+        let config = config_parser::parse_config(&format!(
+            "dual_role:
+  - key: capslock
+    tap: esc
+    hold: leftctrl
+    interrupt: {}
+settings: []",
+            interrupt
+        ))
+        .unwrap();
+        DualRoleKeys::new(&config)
+    }
+
+    fn key(name: &str) -> u16 {
+        keycodes::name_to_code(name) as u16
+    }
+
+    #[test]
+    fn tap_within_the_tapping_term() {
+        let mut keys = dual_role_keys("hold_on_other_key_press");
+        let now = Instant::now();
+        assert_eq!(keys.process(key("capslock"), 1, now), vec![]);
+        assert_eq!(
+            keys.process(key("capslock"), 0, now + Duration::from_millis(50)),
+            vec![(key("esc"), 1), (key("esc"), 0)]
+        );
+        assert_eq!(keys.next_deadline(), None);
+    }
+
+    #[test]
+    fn hold_past_the_tapping_term() {
+        let mut keys = dual_role_keys("hold_on_other_key_press");
+        let now = Instant::now();
+        keys.process(key("capslock"), 1, now);
+        assert_eq!(keys.next_deadline(), Some(now + DEFAULT_TAPPING_TERM));
+        assert_eq!(keys.tick(now + Duration::from_millis(100)), vec![]);
+        assert_eq!(
+            keys.tick(now + DEFAULT_TAPPING_TERM),
+            vec![(key("leftctrl"), 1)]
+        );
+        assert_eq!(
+            keys.process(key("capslock"), 2, now + Duration::from_millis(300)),
+            vec![(key("leftctrl"), 2)]
+        );
+        assert_eq!(
+            keys.process(key("capslock"), 0, now + Duration::from_millis(400)),
+            vec![(key("leftctrl"), 0)]
+        );
+    }
+
+    #[test]
+    fn release_after_the_tapping_term_is_a_hold() {
+        let mut keys = dual_role_keys("hold_on_other_key_press");
+        let now = Instant::now();
+        keys.process(key("capslock"), 1, now);
+        assert_eq!(
+            keys.process(key("capslock"), 0, now + Duration::from_millis(300)),
+            vec![(key("leftctrl"), 1), (key("leftctrl"), 0)]
+        );
+    }
+
+    #[test]
+    fn hold_on_other_key_press() {
+        let mut keys = dual_role_keys("hold_on_other_key_press");
+        let now = Instant::now();
+        keys.process(key("capslock"), 1, now);
+        assert_eq!(
+            keys.process(key("a"), 1, now),
+            vec![(key("leftctrl"), 1), (key("a"), 1)]
+        );
+        assert_eq!(keys.process(key("a"), 0, now), vec![(key("a"), 0)]);
+    }
+
+    #[test]
+    fn permissive_hold() {
+        let mut keys = dual_role_keys("permissive_hold");
+        let now = Instant::now();
+        keys.process(key("capslock"), 1, now);
+        assert_eq!(keys.process(key("a"), 1, now), vec![]);
+        assert_eq!(
+            keys.process(key("a"), 0, now),
+            vec![(key("leftctrl"), 1), (key("a"), 1), (key("a"), 0)]
+        );
+
+        // Released before the other key, it's still a tap.
+        let mut keys = dual_role_keys("permissive_hold");
+        keys.process(key("capslock"), 1, now);
+        keys.process(key("a"), 1, now);
+        assert_eq!(
+            keys.process(key("capslock"), 0, now),
+            vec![(key("esc"), 1), (key("esc"), 0), (key("a"), 1)]
+        );
+    }
+
+    #[test]
+    fn tap_preferred() {
+        let mut keys = dual_role_keys("tap_preferred");
+        let now = Instant::now();
+        keys.process(key("capslock"), 1, now);
+        assert_eq!(keys.process(key("a"), 1, now), vec![]);
+        assert_eq!(keys.process(key("a"), 0, now), vec![]);
+        assert_eq!(
+            keys.process(key("capslock"), 0, now),
+            vec![
+                (key("esc"), 1),
+                (key("esc"), 0),
+                (key("a"), 1),
+                (key("a"), 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn other_keys_pass_through() {
+        let mut keys = dual_role_keys("hold_on_other_key_press");
+        let now = Instant::now();
+        assert_eq!(keys.process(key("a"), 1, now), vec![(key("a"), 1)]);
+        assert_eq!(keys.process(key("a"), 0, now), vec![(key("a"), 0)]);
+    }
+}
@@ -0,0 +1,126 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::utils::user::{self, User};
+
+const PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+// Runs the `exec` commands of remaps as the user owning the sway session,
+// not as root, with only the environment a command in the session needs.
+pub struct Launcher {
+    user: User,
+    env: Vec<(String, String)>,
+    // Changes when sway restarts
+    sway_socket: Arc<Mutex<String>>,
+}
+
+impl Launcher {
+    pub fn new(sway_socket: Arc<Mutex<String>>) -> Result<Launcher, Box<dyn Error>> {
+        let owner = fs::metadata(&*sway_socket.lock().unwrap())?.uid();
+        let user = user::user_for_uid(owner)?;
+        let runtime_dir = format!("/run/user/{}", user.uid);
+        let name = user.name.to_string_lossy().into_owned();
+        let mut env = vec![
+            ("HOME".to_string(), user.home.clone()),
+            ("USER".to_string(), name.clone()),
+            ("LOGNAME".to_string(), name),
+            ("SHELL".to_string(), user.shell.clone()),
+            ("PATH".to_string(), PATH.to_string()),
+        ];
+        if let Some(display) = wayland_display(&runtime_dir) {
+            env.push(("WAYLAND_DISPLAY".to_string(), display));
+        }
+        let bus = format!("{}/bus", runtime_dir);
+        if Path::new(&bus).exists() {
+            env.push((
+                "DBUS_SESSION_BUS_ADDRESS".to_string(),
+                format!("unix:path={}", bus),
+            ));
+        }
+        env.push(("XDG_RUNTIME_DIR".to_string(), runtime_dir));
+        Ok(Launcher {
+            user,
+            env,
+            sway_socket,
+        })
+    }
+
+    fn working_dir(&self) -> &str {
+        if Path::new(&self.user.home).is_dir() {
+            &self.user.home
+        } else {
+            "/"
+        }
+    }
+
+    // Starts `command` with `sh -c` and doesn't wait for it.
+    pub fn spawn(&self, command: &str) {
+        println!("[exec] {}", command);
+        let mut child = Command::new("/bin/sh");
+        child
+            .arg("-c")
+            .arg(command)
+            .env_clear()
+            .envs(self.env.iter().cloned())
+            .env("SWAYSOCK", &*self.sway_socket.lock().unwrap())
+            .current_dir(self.working_dir())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+
+        // Only async-signal-safe calls after the fork: the groups are looked
+        // up beforehand, as that may lock and allocate.
+        let (uid, gid, groups) = (self.user.uid, self.user.gid, self.user.groups.clone());
+        let is_root = unsafe { libc::geteuid() } == 0;
+        unsafe {
+            child.pre_exec(move || {
+                // Out of our session, so it outlives us and doesn't get our
+                // signals.
+                if libc::setsid() < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if is_root
+                    && (libc::setgroups(groups.len(), groups.as_ptr()) < 0
+                        || libc::setgid(gid) < 0
+                        || libc::setuid(uid) < 0)
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        match child.spawn() {
+            // Reaped in the background, so it doesn't linger as a zombie.
+            Ok(mut child) => {
+                thread::spawn(move || child.wait());
+            }
+            Err(e) => println!("[exec] Failed to run {}: {}", command, e),
+        }
+    }
+}
+
+// Our own WAYLAND_DISPLAY if sudo kept it, else the compositor's socket in
+// the runtime directory.
+fn wayland_display(runtime_dir: &str) -> Option<String> {
+    if let Ok(display) = env::var("WAYLAND_DISPLAY") {
+        return Some(display);
+    }
+    let mut sockets: Vec<String> = fs::read_dir(runtime_dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_socket()))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with("wayland-"))
+        .collect();
+    sockets.sort();
+    sockets.into_iter().next()
+}
@@ -1,15 +1,252 @@
-use evdev::{Device, Key};
-use std::error::Error;
+use evdev::{Device, Key, RelativeAxisType};
+use inotify::{EventMask, Inotify, WatchMask};
+use std::fs;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
 
-pub fn get_keyboard_device() -> Result<Device, Box<dyn Error>> {
-    for i in 0..25 {
-        let device = Device::open(format!("/dev/input/event{}", i))?;
-        if device
-            .supported_keys()
-            .map_or(false, |keys| keys.contains(Key::KEY_ENTER))
-        {
-            return Ok(device);
+use crate::utils::config_parser::{DeviceSelection, DeviceSelector};
+use crate::utils::output::VIRTUAL_DEVICE_NAME;
+
+pub fn is_keyboard(device: &Device) -> bool {
+    device
+        .supported_keys()
+        .is_some_and(|keys| keys.contains(Key::KEY_ENTER))
+}
+
+fn is_pointer(device: &Device) -> bool {
+    device
+        .supported_relative_axes()
+        .is_some_and(|axes| axes.contains(RelativeAxisType::REL_X))
+}
+
+// Every /dev/input/eventN chosen by `selection`, or that looks like a keyboard
+// if nothing is chosen explicitly. Never our own virtual device.
+pub fn get_keyboard_devices(selection: &DeviceSelection) -> Vec<(PathBuf, Device)> {
+    event_node_paths()
+        .into_iter()
+        .filter_map(|path| open_keyboard(&path, selection).map(|device| (path, device)))
+        .collect()
+}
+
+pub fn open_keyboard(path: &Path, selection: &DeviceSelection) -> Option<Device> {
+    let device = Device::open(path).ok()?;
+    if is_virtual_device(&device) {
+        return None;
+    }
+    let is_match = |selector: &DeviceSelector| selector_matches(selector, path, &device);
+    if selection.ignore.iter().any(is_match) {
+        return None;
+    }
+    let selected = if selection.only.is_empty() {
+        is_keyboard(&device)
+    } else {
+        selection.only.iter().any(is_match)
+    };
+    if selected {
+        Some(device)
+    } else {
+        None
+    }
+}
+
+pub fn is_virtual_device(device: &Device) -> bool {
+    device.name() == Some(VIRTUAL_DEVICE_NAME)
+}
+
+pub fn selector_matches(selector: &DeviceSelector, path: &Path, device: &Device) -> bool {
+    match selector {
+        // by-id and by-path entries are symlinks to the eventN node
+        DeviceSelector::Path(selected) => {
+            match (fs::canonicalize(selected), fs::canonicalize(path)) {
+                (Ok(selected), Ok(path)) => selected == path,
+                _ => false,
+            }
+        }
+        DeviceSelector::Id(vendor, product) => {
+            device.input_id().vendor() == *vendor && device.input_id().product() == *product
         }
+        DeviceSelector::Name(name) => device.name().is_some_and(|n| n.contains(name.as_str())),
+    }
+}
+
+// /dev/input/eventN, ordered by N
+fn event_node_paths() -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match fs::read_dir("/dev/input") {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| is_event_node(path))
+            .collect(),
+        Err(_) => vec![],
+    };
+    paths.sort_by_key(|path| {
+        path.to_string_lossy()
+            .trim_start_matches("/dev/input/event")
+            .parse::<u32>()
+            .unwrap_or(u32::MAX)
+    });
+    paths
+}
+
+// Print every evdev node with what's needed to select it in the config.
+pub fn list_devices() {
+    let links = device_links();
+    for path in event_node_paths() {
+        let mut device = match Device::open(&path) {
+            Ok(device) => device,
+            Err(e) => {
+                println!("{}\n  cannot open: {}\n", path.display(), e);
+                continue;
+            }
+        };
+        let id = device.input_id();
+        let name = device.name().unwrap_or("").to_string();
+        let grabbed = probe_grabbed(&mut device);
+        let selector = match links.iter().find(|(_, target)| target == &path) {
+            Some((link, _)) => link.display().to_string(),
+            None if id.vendor() != 0 || id.product() != 0 => {
+                format!("{:04x}:{:04x}", id.vendor(), id.product())
+            }
+            None => format!("name:{}", name),
+        };
+        println!("{}", path.display());
+        println!("  name:     {}", name);
+        println!("  phys:     {}", device.physical_path().unwrap_or(""));
+        println!("  id:       {:04x}:{:04x}", id.vendor(), id.product());
+        println!("  keyboard: {}", is_keyboard(&device));
+        println!("  grabbed:  {}", grabbed);
+        println!("  virtual:  {}", is_virtual_device(&device));
+        println!("  selector: {}", selector);
+        println!();
+    }
+}
+
+// EVIOCGRAB fails with EBUSY if someone else holds the device. Trying takes
+// the device from the compositor for an instant, and a key released then
+// would stay stuck there. So only keyboards and pointers are tried, the only
+// devices worth grabbing, and only while no key is down.
+fn probe_grabbed(device: &mut Device) -> &'static str {
+    if !is_keyboard(device) && !is_pointer(device) {
+        return "not checked";
+    }
+    let keys_down = device
+        .get_key_state()
+        .map_or(true, |keys| keys.iter().next().is_some());
+    if keys_down {
+        return "not checked, a key is held";
+    }
+    match device.grab() {
+        Ok(()) => {
+            let _ = device.ungrab();
+            "false"
+        }
+        Err(_) => "true",
+    }
+}
+
+// (symlink, eventN node) for /dev/input/by-id and /dev/input/by-path. by-id
+// comes first, as it survives moving the keyboard to another port.
+fn device_links() -> Vec<(PathBuf, PathBuf)> {
+    let mut links = vec![];
+    for dir in ["/dev/input/by-id", "/dev/input/by-path"] {
+        let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+            Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+            Err(_) => continue,
+        };
+        entries.sort();
+        for link in entries {
+            if let Ok(target) = fs::canonicalize(&link) {
+                links.push((link, target));
+            }
+        }
+    }
+    links
+}
+
+pub fn is_event_node(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with("event"))
+}
+
+// Block until some of `fds` have events to read or `timeout_ms` passes, so
+// the caller can check for shutdown between reads. Returns the indices of the
+// readable fds.
+pub fn wait_readable(fds: &[RawFd], timeout_ms: i32) -> Vec<usize> {
+    let mut pollfds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let ready = unsafe {
+        libc::poll(
+            pollfds.as_mut_ptr(),
+            pollfds.len() as libc::nfds_t,
+            timeout_ms,
+        )
+    };
+    // EINTR (a signal arrived) also lands here and just means "check again".
+    if ready <= 0 {
+        return vec![];
+    }
+    pollfds
+        .iter()
+        .enumerate()
+        .filter(|(_, pollfd)| pollfd.revents != 0)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+pub enum DeviceChange {
+    Added(PathBuf),
+    Removed(PathBuf),
+}
+
+// Watches /dev/input, so keyboards can come and go (docking, Bluetooth
+// reconnects) while we run.
+pub struct DeviceWatcher {
+    inotify: Inotify,
+}
+
+impl DeviceWatcher {
+    pub fn new() -> io::Result<DeviceWatcher> {
+        let mut inotify = Inotify::init()?;
+        // udev fixes up permissions right after creating a node, so ATTRIB
+        // gives us a second chance at a device we couldn't open on CREATE.
+        inotify.add_watch(
+            "/dev/input",
+            WatchMask::CREATE | WatchMask::ATTRIB | WatchMask::DELETE,
+        )?;
+        Ok(DeviceWatcher { inotify })
+    }
+
+    pub fn read_changes(&mut self) -> io::Result<Vec<DeviceChange>> {
+        let mut buffer = [0; 4096];
+        let changes = self
+            .inotify
+            .read_events(&mut buffer)?
+            .filter_map(|event| {
+                let path = Path::new("/dev/input").join(event.name?);
+                if !is_event_node(&path) {
+                    return None;
+                }
+                if event.mask.contains(EventMask::DELETE) {
+                    Some(DeviceChange::Removed(path))
+                } else {
+                    Some(DeviceChange::Added(path))
+                }
+            })
+            .collect();
+        Ok(changes)
+    }
+}
+
+impl AsRawFd for DeviceWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
     }
-    panic!("Cannot infer default device");
 }
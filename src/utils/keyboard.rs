@@ -0,0 +1,595 @@
+use evdev::{Device, InputEvent, InputEventKind, MiscType};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::utils::config_parser::{
+    Action, Config, ConfigKeyCombination, RemapTable, Repeat, SequenceFallback, Setting,
+    TextFallback,
+};
+use crate::utils::dual_role::DualRoleKeys;
+use crate::utils::keycodes;
+use crate::utils::layer::Layers;
+use crate::utils::macros::{self, Macros, Op, Output};
+use crate::utils::modifiers::{self, Modifiers};
+use crate::utils::output::VirtualKeyboard;
+use crate::utils::sequence::SequenceTrie;
+use crate::utils::text::{self, ActiveLayout};
+
+const DEFAULT_SEQUENCE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Where a remap comes from. Tables are looked up again on every use, as the
+// active settings may change under a sequence in progress.
+#[derive(Debug, Clone, Copy)]
+enum TableId {
+    // `remap` of an active setting
+    Setting(usize),
+    // `layers` of an active setting, for one of the layers
+    SettingLayer(usize, usize),
+    // `remap` of a layer, in every application
+    Layer(usize),
+}
+
+// A trie node matched by a key press.
+struct Step {
+    table: TableId,
+    path: Vec<usize>,
+    is_leaf: bool,
+    remap: Option<usize>,
+}
+
+// What a remap sends: `to`, plus the held modifiers it carries through.
+#[derive(Debug, Clone, Default)]
+struct Remap {
+    to: Vec<Action>,
+    carried: Modifiers,
+    repeat: Repeat,
+    // Pause after each key of a multi-step `to`
+    key_delay: Option<Duration>,
+    text_fallback: TextFallback,
+    // Modifiers pressed for a single-key `to`, released with it
+    pressed: Modifiers,
+}
+
+// A multi-stroke `from` that has matched its first key(s) so far.
+struct Sequence {
+    // Where we are: the table's trie, then down `path`
+    table: TableId,
+    path: Vec<usize>,
+    deadline: Instant,
+    // Key events we held back, with the modifiers held at the time, replayed
+    // if the sequence doesn't complete
+    swallowed: Vec<(u16, i32, Modifiers)>,
+}
+
+// A grabbed physical keyboard with its own modifier state, so holding
+// capslock on one keyboard doesn't affect keys typed on another.
+pub struct Keyboard {
+    pub path: PathBuf,
+    pub device: Device,
+    dual_role: DualRoleKeys,
+    layers: Layers,
+    // Modifier keys being held
+    held: Modifiers,
+    // Held modifier keys a remap let go of in the output, until the next
+    // plain key presses them again
+    released: Modifiers,
+    sequence: Option<Sequence>,
+    sequence_timeout: Duration,
+    sequence_fallback: SequenceFallback,
+    // Keys that fired a remap and are still down. Their repeats and release
+    // go to the same target, whatever modifiers are held by then.
+    active_remaps: HashMap<u16, Remap>,
+    // Keys whose press we swallowed, so their repeats and release are too.
+    suppressed: HashSet<u16>,
+    // While macros of this keyboard are queued or playing, its other output
+    // queues up behind them. So it comes out in order, and `released` holds
+    // for the output as it will be once they have played.
+    macros: Macros,
+    layout: ActiveLayout,
+    // The MSC_SCAN that came with the key event being handled. It only goes
+    // out with that key passed through as it is, not with what a remap or
+    // dual-role key sends instead, or later.
+    scan: Option<(u16, InputEvent)>,
+}
+
+impl Keyboard {
+    pub fn new(
+        path: PathBuf,
+        device: Device,
+        config: &Config,
+        macros: &Macros,
+        layout: &ActiveLayout,
+    ) -> Keyboard {
+        Keyboard {
+            path,
+            device,
+            dual_role: DualRoleKeys::new(config),
+            layers: Layers::new(config),
+            held: Modifiers::default(),
+            released: Modifiers::default(),
+            sequence: None,
+            sequence_timeout: config
+                .sequence_timeout
+                .map_or(DEFAULT_SEQUENCE_TIMEOUT, |timeout| timeout.0),
+            sequence_fallback: config.sequence_fallback,
+            active_remaps: HashMap::new(),
+            suppressed: HashSet::new(),
+            macros: macros.handle(),
+            layout: layout.clone(),
+            scan: None,
+        }
+    }
+
+    // When `tick` has something to do, e.g. a dual-role or layer key turning
+    // into a hold or a sequence timing out, even if no further event arrives.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let sequence_deadline = self.sequence.as_ref().map(|sequence| sequence.deadline);
+        [
+            self.dual_role.next_deadline(),
+            self.layers.next_deadline(),
+            sequence_deadline,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    pub fn tick(
+        &mut self,
+        settings: &[Setting],
+        virtual_input: &mut VirtualKeyboard,
+    ) -> io::Result<()> {
+        let now = Instant::now();
+        let mut keys = vec![];
+        for (code, value) in self.dual_role.tick(now) {
+            keys.extend(self.layers.process(code, value, now));
+        }
+        keys.extend(self.layers.tick(now));
+        let is_expired = |sequence: &Option<Sequence>| {
+            sequence
+                .as_ref()
+                .is_some_and(|sequence| now >= sequence.deadline)
+        };
+        if keys.is_empty() && !is_expired(&self.sequence) {
+            return Ok(());
+        }
+        for (code, value) in keys {
+            self.handle_key(code, value, settings, virtual_input)?;
+        }
+        if is_expired(&self.sequence) {
+            let sequence = self.sequence.take().unwrap();
+            let remap = self
+                .table(settings, sequence.table)
+                .and_then(|table| table.trie.descend(&sequence.path))
+                .and_then(|node| node.remap);
+            match remap {
+                // What we have so far is a rule of its own, e.g. `capslock.x`
+                // next to `capslock.x capslock.s`.
+                Some(index) => {
+                    let mut remap = self.remap(settings, sequence.table, index);
+                    self.emit_remap(&mut remap, 1, virtual_input)?;
+                    self.emit_remap(&mut remap, 0, virtual_input)?;
+                }
+                None => self.fallback(sequence, virtual_input)?,
+            }
+        }
+        self.write(vec![Output::Sync], virtual_input)
+    }
+
+    pub fn process_events(
+        &mut self,
+        settings: &[Setting],
+        virtual_input: &mut VirtualKeyboard,
+    ) -> io::Result<()> {
+        let events: Vec<InputEvent> = self.device.fetch_events()?.collect();
+        // The kernel sends it right before its key event.
+        let mut scan = None;
+        for event in events {
+            match event.kind() {
+                InputEventKind::Key(orig_key) => {
+                    println!(
+                        "[InputEventKind::Key] {:?} -> {:?}",
+                        orig_key,
+                        event.value()
+                    );
+                    self.scan = scan.take().map(|scan| (orig_key.code(), scan));
+                    let now = Instant::now();
+                    let keys = self.dual_role.process(orig_key.code(), event.value(), now);
+                    for (code, value) in keys {
+                        for (code, value) in self.layers.process(code, value, now) {
+                            self.handle_key(code, value, settings, virtual_input)?;
+                        }
+                    }
+                    self.scan = None;
+                }
+                InputEventKind::Misc(MiscType::MSC_SCAN) => scan = Some(event),
+                InputEventKind::Synchronization(_) => {
+                    self.write(vec![Output::Sync], virtual_input)?
+                }
+                // EV_REL, EV_ABS, EV_LED, ... from trackpoints, wheels and
+                // knobs on the same device.
+                _ => self.write(vec![Output::Event(event)], virtual_input)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_key(
+        &mut self,
+        code: u16,
+        value: i32,
+        settings: &[Setting],
+        virtual_input: &mut VirtualKeyboard,
+    ) -> io::Result<()> {
+        // capture modifier keys
+        match (Modifiers::of_code(code), value) {
+            (Some(key), 1) => self.held = self.held.union(key),
+            (Some(key), 0) => {
+                self.held = self.held.difference(key);
+                self.released = self.released.difference(key);
+            }
+            (_, _) => {}
+        }
+
+        if let Some(mut remap) = self.active_remaps.get(&code).cloned() {
+            if value == 0 {
+                self.active_remaps.remove(&code);
+            }
+            return self.emit_remap(&mut remap, value, virtual_input);
+        }
+        if self.suppressed.contains(&code) {
+            if value == 0 {
+                self.suppressed.remove(&code);
+                let held = self.held;
+                if let Some(sequence) = self.sequence.as_mut() {
+                    sequence.swallowed.push((code, 0, held));
+                }
+            }
+            return Ok(());
+        }
+
+        if settings.is_empty() && self.layers.active().is_empty() {
+            let mut ops = self.scan_of(code);
+            ops.push(Output::Key(code as i32, value));
+            return self.write(ops, virtual_input);
+        }
+        if value == 1 && self.handle_press(code, settings, virtual_input)? {
+            return Ok(());
+        }
+        self.passthrough(code, value, virtual_input)
+    }
+
+    // Walk the sequence tries with a key press. Returns whether the press
+    // was taken by a remap or a sequence in progress.
+    fn handle_press(
+        &mut self,
+        code: u16,
+        settings: &[Setting],
+        virtual_input: &mut VirtualKeyboard,
+    ) -> io::Result<bool> {
+        let keyname = keycodes::code_to_name(code);
+        let held = self.modifiers_of(code);
+        let find = |node: &SequenceTrie| {
+            node.find_key(keyname, held)
+                .map(|(i, node)| (i, node.is_leaf(), node.remap))
+        };
+
+        if let Some(sequence) = self.sequence.take() {
+            let next = self
+                .table(settings, sequence.table)
+                .and_then(|table| table.trie.descend(&sequence.path))
+                .and_then(find);
+            if let Some((i, is_leaf, remap)) = next {
+                let mut path = sequence.path;
+                path.push(i);
+                let step = Step {
+                    table: sequence.table,
+                    path,
+                    is_leaf,
+                    remap,
+                };
+                return self.step(step, code, sequence.swallowed, settings, virtual_input);
+            }
+            // A modifier pressed for the next stroke, e.g. ctrl again for
+            // `C-x C-s`, doesn't end the sequence.
+            if Modifiers::of_code(code).is_some() {
+                self.sequence = Some(sequence);
+                return Ok(false);
+            }
+            self.fallback(sequence, virtual_input)?;
+        }
+
+        for table in self.tables(settings) {
+            let found = self
+                .table(settings, table)
+                .and_then(|remap_table| find(&remap_table.trie));
+            if let Some((i, is_leaf, remap)) = found {
+                let step = Step {
+                    table,
+                    path: vec![i],
+                    is_leaf,
+                    remap,
+                };
+                return self.step(step, code, vec![], settings, virtual_input);
+            }
+        }
+        Ok(false)
+    }
+
+    // Tables to look a key up in, first match wins: the active layers, the
+    // most recently held first, each with the settings' own remaps for it
+    // before its global ones. Then the settings' base remaps.
+    fn tables(&self, settings: &[Setting]) -> Vec<TableId> {
+        let mut tables = vec![];
+        for layer in self.layers.active() {
+            let name = &self.layers.layers[layer].name;
+            for (s, setting) in settings.iter().enumerate() {
+                if setting.layers.contains_key(name) {
+                    tables.push(TableId::SettingLayer(s, layer));
+                }
+            }
+            tables.push(TableId::Layer(layer));
+        }
+        tables.extend((0..settings.len()).map(TableId::Setting));
+        tables
+    }
+
+    fn table<'a>(&'a self, settings: &'a [Setting], id: TableId) -> Option<&'a RemapTable> {
+        match id {
+            TableId::Setting(s) => settings.get(s).map(|setting| &setting.remap),
+            TableId::SettingLayer(s, layer) => {
+                let name = &self.layers.layers.get(layer)?.name;
+                settings.get(s)?.layers.get(name)
+            }
+            TableId::Layer(layer) => self.layers.layers.get(layer).map(|layer| &layer.remap),
+        }
+    }
+
+    fn remap(&self, settings: &[Setting], id: TableId, index: usize) -> Remap {
+        let rule = match self.table(settings, id) {
+            Some(table) => &table.rules[index],
+            None => return Remap::default(),
+        };
+        let carried = match (rule.preserve_modifiers, rule.from.last()) {
+            // Modifiers used by `from` are consumed, the rest carry through.
+            (Some(true), Some(from)) => from
+                .modifiers
+                .iter()
+                .fold(self.held, |held, &keys| held.difference(keys)),
+            _ => Modifiers::default(),
+        };
+        // Layers in every application follow the first active setting.
+        let setting = match id {
+            TableId::Setting(s) | TableId::SettingLayer(s, _) => settings.get(s),
+            TableId::Layer(_) => settings.first(),
+        };
+        Remap {
+            to: rule.to.clone(),
+            carried,
+            repeat: rule.repeat,
+            key_delay: setting.and_then(|setting| setting.key_delay.map(|delay| delay.0)),
+            text_fallback: setting.map_or(TextFallback::default(), |setting| setting.text_fallback),
+            pressed: Modifiers::default(),
+        }
+    }
+
+    fn step(
+        &mut self,
+        step: Step,
+        code: u16,
+        mut swallowed: Vec<(u16, i32, Modifiers)>,
+        settings: &[Setting],
+        virtual_input: &mut VirtualKeyboard,
+    ) -> io::Result<bool> {
+        if step.is_leaf {
+            // Every leaf ends a `from`.
+            let mut remap = self.remap(settings, step.table, step.remap.unwrap());
+            self.emit_remap(&mut remap, 1, virtual_input)?;
+            self.active_remaps.insert(code, remap);
+            return Ok(true);
+        }
+        println!("[sequence] Waiting for the next key");
+        swallowed.push((code, 1, self.modifiers_of(code)));
+        self.suppressed.insert(code);
+        self.sequence = Some(Sequence {
+            table: step.table,
+            path: step.path,
+            deadline: Instant::now() + self.sequence_timeout,
+            swallowed,
+        });
+        Ok(true)
+    }
+
+    // The sequence didn't complete; send or drop what it swallowed.
+    fn fallback(
+        &mut self,
+        sequence: Sequence,
+        virtual_input: &mut VirtualKeyboard,
+    ) -> io::Result<()> {
+        println!("[sequence] No match. {:?}", self.sequence_fallback);
+        if self.sequence_fallback == SequenceFallback::Drop {
+            return Ok(());
+        }
+        let mut ops = vec![];
+        for (code, value, held) in sequence.swallowed {
+            // Its press is out now, so a later release has to be too.
+            self.suppressed.remove(&code);
+            // With the modifiers of back then, which may be up by now.
+            match value {
+                0 => ops.push(Output::Key(code as i32, 0)),
+                _ => self.emit_key(code as i32, held, value, &mut ops),
+            }
+        }
+        self.write(ops, virtual_input)
+    }
+
+    // The held modifiers that apply to `code`. A modifier pressed as the key
+    // itself, e.g. `leftctrl.leftshift`, isn't one of its own modifiers.
+    fn modifiers_of(&self, code: u16) -> Modifiers {
+        self.held
+            .difference(Modifiers::of_code(code).unwrap_or_default())
+    }
+
+    fn emit_remap(
+        &mut self,
+        remap: &mut Remap,
+        value: i32,
+        virtual_input: &mut VirtualKeyboard,
+    ) -> io::Result<()> {
+        let carried = remap.carried.intersection(self.held);
+        // A single key is held with its modifiers and repeats like the key
+        // that was pressed. Only the press sets the modifiers up, as clients
+        // repeat a key with whatever modifiers are down.
+        if let [Action::Key(to)] = remap.to.as_slice() {
+            let code = keycodes::name_to_code(&to.keyname);
+            let mut ops = vec![];
+            match value {
+                1 => {
+                    println!("[remap.to] {}", to.value);
+                    let wanted =
+                        modifiers::resolve(&to.modifiers, to.optional, self.held).union(carried);
+                    remap.pressed = self.hold_modifiers(wanted, &mut ops);
+                    ops.push(Output::Key(code, 1));
+                }
+                0 => {
+                    ops.push(Output::Key(code, 0));
+                    // Unless they are physically down by now
+                    let down = self.held.difference(self.released);
+                    for key in remap.pressed.difference(down).key_names() {
+                        ops.push(Output::Key(keycodes::name_to_code(key), 0));
+                    }
+                }
+                _ => ops.push(Output::Key(code, value)),
+            }
+            return self.write(ops, virtual_input);
+        }
+        // Anything else is a macro, played out on press, with nothing to
+        // hold down.
+        if value == 1 || (value == 2 && remap.repeat == Repeat::Replay) {
+            let ops = self.macro_ops(remap, carried);
+            self.macros.run(ops);
+        }
+        Ok(())
+    }
+
+    fn macro_ops(&mut self, remap: &Remap, carried: Modifiers) -> Vec<Op> {
+        let mut ops = vec![];
+        for action in remap.to.iter() {
+            let keys = match action {
+                Action::Key(to) => vec![to.clone()],
+                Action::Repeat(to, times) => vec![to.clone(); *times],
+                Action::Text(text) => text::keys(text, remap.text_fallback, self.layout.is_us()),
+                Action::Press(key) => {
+                    push_key(key, 1, remap.key_delay, &mut ops);
+                    continue;
+                }
+                Action::Release(key) => {
+                    push_key(key, 0, remap.key_delay, &mut ops);
+                    continue;
+                }
+                Action::Sleep(duration) => {
+                    ops.push(Op::Sleep(*duration));
+                    continue;
+                }
+                Action::Exec(command) => {
+                    ops.push(Op::Exec(command.clone()));
+                    continue;
+                }
+                Action::Sway(command) => {
+                    ops.push(Op::Sway(command.clone()));
+                    continue;
+                }
+            };
+            for to in keys.iter() {
+                let mut outputs = vec![];
+                self.emit_step(to, carried, 1, &mut outputs);
+                self.emit_step(to, carried, 0, &mut outputs);
+                outputs.push(Output::Sync);
+                ops.extend(outputs.into_iter().map(Op::Output));
+                ops.extend(remap.key_delay.map(Op::Sleep));
+            }
+        }
+        ops
+    }
+
+    fn emit_step(
+        &mut self,
+        to: &ConfigKeyCombination,
+        carried: Modifiers,
+        value: i32,
+        ops: &mut Vec<Output>,
+    ) {
+        println!("[remap.to] {}", to.value);
+        let wanted = modifiers::resolve(&to.modifiers, to.optional, self.held).union(carried);
+        self.emit_key(keycodes::name_to_code(&to.keyname), wanted, value, ops);
+    }
+
+    // `code` with exactly the `wanted` modifiers down. Held modifiers it
+    // doesn't want are let go; `passthrough` presses them again for the next
+    // plain key.
+    fn emit_key(&mut self, code: i32, wanted: Modifiers, value: i32, ops: &mut Vec<Output>) {
+        let pressed = self.hold_modifiers(wanted, ops);
+        ops.push(Output::Key(code, value));
+        for key in pressed.key_names() {
+            ops.push(Output::Key(keycodes::name_to_code(key), 0));
+        }
+    }
+
+    // Gets exactly the `wanted` modifiers down in the output. Returns the
+    // ones it pressed, which are up to the caller to release.
+    fn hold_modifiers(&mut self, wanted: Modifiers, ops: &mut Vec<Output>) -> Modifiers {
+        let down = self.held.difference(self.released);
+        let pressed = wanted.difference(down);
+        for key in down.difference(wanted).key_names() {
+            ops.push(Output::Key(keycodes::name_to_code(key), 0));
+        }
+        self.released = self.released.union(down.difference(wanted));
+        for key in pressed.key_names() {
+            ops.push(Output::Key(keycodes::name_to_code(key), 1));
+        }
+        pressed
+    }
+
+    fn passthrough(
+        &mut self,
+        code: u16,
+        value: i32,
+        virtual_input: &mut VirtualKeyboard,
+    ) -> io::Result<()> {
+        let mut ops = vec![];
+        for key in self.held.key_names() {
+            ops.push(Output::Key(keycodes::name_to_code(key), 1));
+        }
+        self.released = Modifiers::default();
+        ops.extend(self.scan_of(code));
+        ops.push(Output::Key(code as i32, value));
+        self.write(ops, virtual_input)
+    }
+
+    // The MSC_SCAN to send with `code`, if it's the key that came with it.
+    fn scan_of(&mut self, code: u16) -> Vec<Output> {
+        match self.scan.take() {
+            Some((scanned, event)) if scanned == code => vec![Output::Event(event)],
+            _ => vec![],
+        }
+    }
+
+    // Writes `ops` now, or after the macros still to play.
+    fn write(&self, ops: Vec<Output>, virtual_input: &mut VirtualKeyboard) -> io::Result<()> {
+        if self.macros.is_pending() {
+            self.macros.run(ops.into_iter().map(Op::Output).collect());
+            return Ok(());
+        }
+        macros::write(&ops, virtual_input)
+    }
+}
+
+// A `press` or `release` step of a macro, leaving modifiers alone.
+fn push_key(key: &str, value: i32, key_delay: Option<Duration>, ops: &mut Vec<Op>) {
+    println!("[remap.to] {} {}", key, value);
+    ops.push(Op::Output(Output::Key(keycodes::name_to_code(key), value)));
+    ops.push(Op::Output(Output::Sync));
+    ops.extend(key_delay.map(Op::Sleep));
+}
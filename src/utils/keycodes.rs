@@ -627,7 +627,12 @@ pub fn code_to_name(x: u16) -> &'static str {
 }
 
 pub fn name_to_code(x: &str) -> i32 {
-    match x {
+    try_name_to_code(x).unwrap_or_else(|| panic!("Unknown key name {}", x))
+}
+
+// For names from the config, which may be misspelled.
+pub fn try_name_to_code(x: &str) -> Option<i32> {
+    Some(match x {
         "reserved" => 0,
         "esc" => 1,
         "1" => 2,
@@ -1229,6 +1234,6 @@ pub fn name_to_code(x: &str) -> i32 {
         "btn_trigger_happy39" => 0x2e6,
         "btn_trigger_happy40" => 0x2e7,
         "max" => 0x2ff,
-        _ => panic!("Unknown key name {}", x),
-    }
+        _ => return None,
+    })
 }
@@ -0,0 +1,183 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use crate::utils::config_parser::{Config, LayerActivation, RemapTable, DEFAULT_TAPPING_TERM};
+use crate::utils::keycodes;
+
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub name: String,
+    // Remaps of this layer in every application
+    pub remap: RemapTable,
+    code: u16,
+    activation: Vec<LayerActivation>,
+    tapping_term: Duration,
+}
+
+// The layer key is down, but we don't know yet if it's a tap or a hold.
+struct Pending {
+    layer: usize,
+    deadline: Instant,
+}
+
+// Tracks layer keys and which layers they activated, for a single keyboard.
+// Layer keys are taken out of the key stream; everything else passes.
+pub struct Layers {
+    pub layers: Vec<Layer>,
+    pending: Option<Pending>,
+    // Layer keys being held, by key code
+    momentary: Vec<(u16, usize)>,
+    toggled: HashSet<usize>,
+    locked: HashSet<usize>,
+    last_tap: Option<(usize, Instant)>,
+    // Layer keys that turned out to be plain key presses
+    plain: HashSet<u16>,
+}
+
+impl Layers {
+    pub fn new(config: &Config) -> Layers {
+        let layers = config
+            .layers
+            .iter()
+            .map(|setting| Layer {
+                name: setting.name.clone(),
+                remap: setting.remap.clone(),
+                code: keycodes::name_to_code(&setting.key) as u16,
+                activation: setting.activation.clone(),
+                tapping_term: setting
+                    .tapping_term
+                    .or(config.tapping_term)
+                    .map_or(DEFAULT_TAPPING_TERM, |term| term.0),
+            })
+            .collect();
+        Layers {
+            layers,
+            pending: None,
+            momentary: vec![],
+            toggled: HashSet::new(),
+            locked: HashSet::new(),
+            last_tap: None,
+            plain: HashSet::new(),
+        }
+    }
+
+    // Indices of the active layers, the most recently held first.
+    pub fn active(&self) -> Vec<usize> {
+        let mut active: Vec<usize> = self.momentary.iter().rev().map(|&(_, i)| i).collect();
+        for i in 0..self.layers.len() {
+            if (self.toggled.contains(&i) || self.locked.contains(&i)) && !active.contains(&i) {
+                active.push(i);
+            }
+        }
+        active
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|pending| pending.deadline)
+    }
+
+    pub fn process(&mut self, code: u16, value: i32, now: Instant) -> Vec<(u16, i32)> {
+        if let Some(pending) = &self.pending {
+            let layer = pending.layer;
+            if self.layers[layer].code == code {
+                return match value {
+                    0 => {
+                        self.pending = None;
+                        self.tap(layer, now)
+                    }
+                    _ => vec![],
+                };
+            }
+            // Another key: the layer key is being held.
+            if value == 1 {
+                let mut out = self.hold(layer);
+                out.push((code, value));
+                return out;
+            }
+            return vec![(code, value)];
+        }
+
+        if self.plain.contains(&code) {
+            if value == 0 {
+                self.plain.remove(&code);
+            }
+            return vec![(code, value)];
+        }
+        if let Some(position) = self.momentary.iter().position(|&(c, _)| c == code) {
+            if value == 0 {
+                let (_, layer) = self.momentary.remove(position);
+                println!("[layer] {} off", self.layers[layer].name);
+            }
+            return vec![];
+        }
+        match self.layers.iter().position(|layer| layer.code == code) {
+            Some(layer) if value == 1 => {
+                self.pending = Some(Pending {
+                    layer,
+                    deadline: now + self.layers[layer].tapping_term,
+                });
+                vec![]
+            }
+            _ => vec![(code, value)],
+        }
+    }
+
+    pub fn tick(&mut self, now: Instant) -> Vec<(u16, i32)> {
+        match &self.pending {
+            Some(pending) if now >= pending.deadline => self.hold(pending.layer),
+            _ => vec![],
+        }
+    }
+
+    fn hold(&mut self, layer: usize) -> Vec<(u16, i32)> {
+        self.pending = None;
+        let code = self.layers[layer].code;
+        if !self.layers[layer]
+            .activation
+            .contains(&LayerActivation::Momentary)
+        {
+            self.plain.insert(code);
+            return vec![(code, 1)];
+        }
+        println!("[layer] {} on while held", self.layers[layer].name);
+        self.momentary.push((code, layer));
+        vec![]
+    }
+
+    // A tap toggles the layer, a double tap locks or unlocks it. A locked
+    // layer ignores single taps, so it isn't left by accident.
+    fn tap(&mut self, layer: usize, now: Instant) -> Vec<(u16, i32)> {
+        let definition = &self.layers[layer];
+        let is_double_tap = matches!(
+            self.last_tap,
+            Some((last, at)) if last == layer && now.duration_since(at) <= definition.tapping_term
+        );
+        self.last_tap = Some((layer, now));
+
+        if is_double_tap && definition.activation.contains(&LayerActivation::Lock) {
+            self.last_tap = None;
+            if self.locked.remove(&layer) {
+                self.toggled.remove(&layer);
+                println!("[layer] {} unlocked", definition.name);
+            } else {
+                self.locked.insert(layer);
+                println!("[layer] {} locked", definition.name);
+            }
+            return vec![];
+        }
+        if self.locked.contains(&layer) {
+            return vec![];
+        }
+        if definition.activation.contains(&LayerActivation::Toggle) {
+            if self.toggled.remove(&layer) {
+                println!("[layer] {} toggled off", definition.name);
+            } else {
+                self.toggled.insert(layer);
+                println!("[layer] {} toggled on", definition.name);
+            }
+            return vec![];
+        }
+        // Without toggle, a tap is just the key itself, e.g. space.
+        vec![(definition.code, 1), (definition.code, 0)]
+    }
+}
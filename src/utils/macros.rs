@@ -0,0 +1,117 @@
+use evdev::InputEvent;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use uinput_sys::EV_KEY;
+
+use crate::utils::exec::Launcher;
+use crate::utils::output::VirtualKeyboard;
+use crate::utils::wayland::SwayCommands;
+
+// A single write to the virtual keyboard.
+#[derive(Debug, Clone)]
+pub enum Output {
+    Key(i32, i32),
+    // Forwarded from the real device as it is
+    Event(InputEvent),
+    Sync,
+}
+
+// A step of a macro: a write, a pause between them, or a command to run.
+#[derive(Debug, Clone)]
+pub enum Op {
+    Output(Output),
+    Sleep(Duration),
+    Exec(String),
+    Sway(String),
+}
+
+// Plays macros on a thread of its own, so their sleeps and key delays don't
+// hold up the keyboards.
+#[derive(Clone)]
+pub struct Macros {
+    sender: Sender<(Vec<Op>, Arc<AtomicUsize>)>,
+    // Macros run through this handle that haven't finished playing
+    pending: Arc<AtomicUsize>,
+}
+
+impl Macros {
+    // `sway` has a connection of its own, as the one we subscribe with only
+    // receives events.
+    pub fn spawn(
+        virtual_input: Arc<Mutex<VirtualKeyboard>>,
+        launcher: Launcher,
+        mut sway: SwayCommands,
+    ) -> Macros {
+        let (sender, receiver) = mpsc::channel::<(Vec<Op>, Arc<AtomicUsize>)>();
+        thread::spawn(move || {
+            for (ops, pending) in receiver {
+                if let Err(e) = play(&ops, &virtual_input, &launcher, &mut sway) {
+                    println!("[macro] Failed to write events: {}", e);
+                }
+                pending.fetch_sub(1, Ordering::SeqCst);
+            }
+        });
+        Macros {
+            sender,
+            pending: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // A handle on the same thread, keeping track of its own macros. One for
+    // each keyboard, so it can tell when its own output has to wait.
+    pub fn handle(&self) -> Macros {
+        Macros {
+            sender: self.sender.clone(),
+            pending: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // Whether macros run through this handle are still queued or playing.
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::SeqCst) > 0
+    }
+
+    pub fn run(&self, ops: Vec<Op>) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        if self.sender.send((ops, Arc::clone(&self.pending))).is_err() {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            println!("[macro] The macro thread is gone");
+        }
+    }
+}
+
+fn play(
+    ops: &[Op],
+    virtual_input: &Mutex<VirtualKeyboard>,
+    launcher: &Launcher,
+    sway: &mut SwayCommands,
+) -> io::Result<()> {
+    for op in ops {
+        match op {
+            // Without the lock, so the keyboards can go on meanwhile.
+            Op::Sleep(duration) => thread::sleep(*duration),
+            Op::Exec(command) => launcher.spawn(command),
+            Op::Sway(command) => sway.run(command),
+            Op::Output(output) => write(
+                std::slice::from_ref(output),
+                &mut virtual_input.lock().unwrap(),
+            )?,
+        }
+    }
+    Ok(())
+}
+
+pub fn write(outputs: &[Output], virtual_input: &mut VirtualKeyboard) -> io::Result<()> {
+    for output in outputs {
+        match *output {
+            Output::Key(code, value) => virtual_input.write(EV_KEY, code, value)?,
+            Output::Event(event) => virtual_input.write_event(&event)?,
+            Output::Sync => virtual_input.synchronize()?,
+        }
+    }
+    Ok(())
+}
@@ -1,4 +1,15 @@
+pub mod cli;
 pub mod config_parser;
+pub mod dual_role;
+pub mod exec;
 pub mod input;
+pub mod keyboard;
 pub mod keycodes;
+pub mod layer;
+pub mod macros;
+pub mod modifiers;
+pub mod output;
+pub mod sequence;
+pub mod text;
+pub mod user;
 pub mod wayland;
@@ -0,0 +1,248 @@
+use crate::utils::keycodes;
+
+// Modifier keys, one bit each. capslock is commonly turned into a modifier
+// with xkb options like `caps:ctrl_modifier`, so it gets a bit of its own.
+const KEYS: [&str; 9] = [
+    "leftctrl",
+    "rightctrl",
+    "leftshift",
+    "rightshift",
+    "leftalt",
+    "rightalt",
+    "leftmeta",
+    "rightmeta",
+    "capslock",
+];
+
+// A set of modifier keys, e.g. the ones being held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers(u16);
+
+impl Modifiers {
+    pub fn of_key(name: &str) -> Option<Modifiers> {
+        KEYS.iter()
+            .position(|&key| key == name)
+            .map(|bit| Modifiers(1 << bit))
+    }
+
+    pub fn of_code(code: u16) -> Option<Modifiers> {
+        Modifiers::of_key(keycodes::code_to_name(code))
+    }
+
+    pub fn all() -> Modifiers {
+        Modifiers((1 << KEYS.len()) - 1)
+    }
+
+    fn of_keys(names: &[&str]) -> Modifiers {
+        names
+            .iter()
+            .filter_map(|name| Modifiers::of_key(name))
+            .fold(Modifiers::default(), Modifiers::union)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn intersects(self, other: Modifiers) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn intersection(self, other: Modifiers) -> Modifiers {
+        Modifiers(self.0 & other.0)
+    }
+
+    pub fn union(self, other: Modifiers) -> Modifiers {
+        Modifiers(self.0 | other.0)
+    }
+
+    pub fn difference(self, other: Modifiers) -> Modifiers {
+        Modifiers(self.0 & !other.0)
+    }
+
+    // The first key of the set, i.e. the left one of an either-side alias.
+    fn first(self) -> Modifiers {
+        Modifiers(self.0 & self.0.wrapping_neg())
+    }
+
+    pub fn key_names(self) -> impl Iterator<Item = &'static str> {
+        KEYS.iter()
+            .enumerate()
+            .filter(move |(bit, _)| self.0 & (1 << bit) != 0)
+            .map(|(_, &key)| key)
+    }
+}
+
+// The modifiers a modifier name in the config stands for. Each entry is a
+// set of keys, any one of which has to be held. `ctrl` is either ctrl key,
+// and `hyper` is ctrl, shift, alt and meta together.
+pub fn parse_modifier(name: &str) -> Option<Vec<Modifiers>> {
+    let ctrl = Modifiers::of_keys(&["leftctrl", "rightctrl"]);
+    let shift = Modifiers::of_keys(&["leftshift", "rightshift"]);
+    let alt = Modifiers::of_keys(&["leftalt", "rightalt"]);
+    let meta = Modifiers::of_keys(&["leftmeta", "rightmeta"]);
+    match name {
+        "ctrl" => Some(vec![ctrl]),
+        "shift" => Some(vec![shift]),
+        "alt" => Some(vec![alt]),
+        "meta" | "super" => Some(vec![meta]),
+        "hyper" => Some(vec![ctrl, shift, alt, meta]),
+        _ => Modifiers::of_key(name).map(|key| vec![key]),
+    }
+}
+
+// A wildcard in brackets: `[shift?]` may or may not be held, `[any]` lets
+// any modifier be held.
+pub fn parse_wildcard(segment: &str) -> Option<Modifiers> {
+    let inner = segment.strip_prefix('[')?.strip_suffix(']')?;
+    if inner == "any" {
+        return Some(Modifiers::all());
+    }
+    let keys = parse_modifier(inner.strip_suffix('?')?)?;
+    Some(
+        keys.into_iter()
+            .fold(Modifiers::default(), Modifiers::union),
+    )
+}
+
+// Whether `held` is exactly what `required` asks for: a key of every entry,
+// and nothing else but the `optional` ones.
+pub fn matches(required: &[Modifiers], optional: Modifiers, held: Modifiers) -> bool {
+    let allowed = required.iter().fold(optional, |all, &keys| all.union(keys));
+    required.iter().all(|&keys| keys.intersects(held)) && held.difference(allowed).is_empty()
+}
+
+// The keys to hold down for `required`. Keys already held are kept, so
+// `ctrl` with rightctrl held doesn't press leftctrl on top of it, and so are
+// held `optional` ones.
+pub fn resolve(required: &[Modifiers], optional: Modifiers, held: Modifiers) -> Modifiers {
+    let kept = optional.intersection(held);
+    required.iter().fold(kept, |keys, &alternatives| {
+        if alternatives.intersects(held) {
+            keys.union(alternatives.intersection(held))
+        } else {
+            keys.union(alternatives.first())
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(names: &[&str]) -> Modifiers {
+        Modifiers::of_keys(names)
+    }
+
+    #[test]
+    fn exact_match() {
+        let required = vec![keys(&["capslock"])];
+        assert!(matches(
+            &required,
+            Modifiers::default(),
+            keys(&["capslock"])
+        ));
+        assert!(!matches(&required, Modifiers::default(), keys(&[])));
+        assert!(!matches(
+            &required,
+            Modifiers::default(),
+            keys(&["capslock", "leftshift"])
+        ));
+        assert!(matches(&[], Modifiers::default(), keys(&[])));
+        assert!(!matches(&[], Modifiers::default(), keys(&["leftalt"])));
+    }
+
+    #[test]
+    fn either_side() {
+        let ctrl = parse_modifier("ctrl").unwrap();
+        assert!(matches(&ctrl, Modifiers::default(), keys(&["leftctrl"])));
+        assert!(matches(&ctrl, Modifiers::default(), keys(&["rightctrl"])));
+        assert!(matches(
+            &ctrl,
+            Modifiers::default(),
+            keys(&["leftctrl", "rightctrl"])
+        ));
+        assert_eq!(parse_modifier("super"), parse_modifier("meta"));
+        assert_eq!(parse_modifier("leftalt"), Some(vec![keys(&["leftalt"])]));
+        assert_eq!(parse_modifier("a"), None);
+    }
+
+    #[test]
+    fn hyper() {
+        let hyper = parse_modifier("hyper").unwrap();
+        let held = keys(&["leftctrl", "rightshift", "leftalt", "leftmeta"]);
+        assert!(matches(&hyper, Modifiers::default(), held));
+        assert!(!matches(
+            &hyper,
+            Modifiers::default(),
+            keys(&["leftctrl", "leftshift", "leftalt"])
+        ));
+    }
+
+    #[test]
+    fn resolve_prefers_held_keys() {
+        let ctrl = parse_modifier("ctrl").unwrap();
+        assert_eq!(
+            resolve(&ctrl, Modifiers::default(), keys(&[])),
+            keys(&["leftctrl"])
+        );
+        assert_eq!(
+            resolve(&ctrl, Modifiers::default(), keys(&["rightctrl"])),
+            keys(&["rightctrl"])
+        );
+        assert_eq!(
+            resolve(&ctrl, Modifiers::default(), keys(&["leftshift"])),
+            keys(&["leftctrl"])
+        );
+    }
+
+    #[test]
+    fn key_names() {
+        let names: Vec<&str> = keys(&["capslock", "leftctrl"]).key_names().collect();
+        assert_eq!(names, vec!["leftctrl", "capslock"]);
+        assert_eq!(Modifiers::all().key_names().count(), KEYS.len());
+    }
+
+    #[test]
+    fn wildcards() {
+        assert_eq!(
+            parse_wildcard("[shift?]"),
+            Some(keys(&["leftshift", "rightshift"]))
+        );
+        assert_eq!(parse_wildcard("[any]"), Some(Modifiers::all()));
+        assert_eq!(parse_wildcard("[shift]"), None);
+        assert_eq!(parse_wildcard("shift?"), None);
+        assert_eq!(parse_wildcard("[a?]"), None);
+    }
+
+    #[test]
+    fn optional_modifiers() {
+        let ctrl = parse_modifier("ctrl").unwrap();
+        let shift = parse_wildcard("[shift?]").unwrap();
+        assert!(matches(&ctrl, shift, keys(&["leftctrl"])));
+        assert!(matches(&ctrl, shift, keys(&["leftctrl", "rightshift"])));
+        assert!(!matches(&ctrl, shift, keys(&["rightshift"])));
+        assert!(!matches(&ctrl, shift, keys(&["leftctrl", "leftalt"])));
+        assert!(matches(
+            &ctrl,
+            Modifiers::all(),
+            keys(&["leftctrl", "leftalt"])
+        ));
+        assert!(matches(&[], Modifiers::all(), keys(&[])));
+    }
+
+    #[test]
+    fn resolve_keeps_optional_keys() {
+        let ctrl = parse_modifier("ctrl").unwrap();
+        let shift = parse_wildcard("[shift?]").unwrap();
+        assert_eq!(
+            resolve(&ctrl, shift, keys(&["leftctrl", "rightshift"])),
+            keys(&["leftctrl", "rightshift"])
+        );
+        assert_eq!(resolve(&ctrl, shift, keys(&[])), keys(&["leftctrl"]));
+        assert_eq!(
+            resolve(&[], Modifiers::all(), keys(&["leftalt"])),
+            keys(&["leftalt"])
+        );
+    }
+}
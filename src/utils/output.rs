@@ -0,0 +1,221 @@
+use evdev::{Device, EventType, InputEvent};
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::time::{Duration, SystemTime};
+use uinput_sys::{EV_ABS, EV_KEY, EV_LED, EV_MSC, EV_REL, EV_SYN, SYN_REPORT};
+
+// Used by `input` to recognise (and never grab) our own device.
+pub const VIRTUAL_DEVICE_NAME: &str = "sway-remap virtual keyboard";
+
+// Keys we may emit from remaps even if the real keyboard lacks them:
+// everything below BTN_MISC and the KEY_* block after the BTN_* ranges.
+const EMITTABLE_KEYS: [std::ops::Range<i32>; 2] = [1..0x100, 0x160..0x2c0];
+
+// The single uinput device all grabbed keyboards write remapped events to.
+// Its capabilities are the union of the grabbed keyboards', so trackpoints,
+// scroll wheels and knobs keep working.
+// It also remembers which keys it currently holds down, so they can all be
+// released on shutdown. Otherwise a modifier like capslock stays logically
+// pressed after we exit.
+pub struct VirtualKeyboard {
+    fd: libc::c_int,
+    held_keys: HashSet<i32>,
+    capabilities: HashSet<(i32, i32)>,
+}
+
+impl VirtualKeyboard {
+    pub fn new(sources: &[&Device]) -> io::Result<VirtualKeyboard> {
+        let path = CString::new("/dev/uinput").unwrap();
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_NONBLOCK) };
+        if fd < 0 {
+            println!("Please load uinput module; Possibly you should run `modprobe uinput`");
+            return Err(io::Error::last_os_error());
+        }
+        // Constructed before setup so that the fd is closed on any error.
+        let mut virtual_keyboard = VirtualKeyboard {
+            fd,
+            held_keys: HashSet::new(),
+            capabilities: HashSet::new(),
+        };
+        virtual_keyboard.setup(sources)?;
+        Ok(virtual_keyboard)
+    }
+
+    // uinput devices can't gain capabilities after creation, so a hotplugged
+    // keyboard with e.g. a trackpoint needs a new virtual device.
+    pub fn covers(&self, source: &Device) -> bool {
+        capabilities_of(source)
+            .iter()
+            .all(|capability| self.capabilities.contains(capability))
+    }
+
+    fn setup(&mut self, sources: &[&Device]) -> io::Result<()> {
+        let mut user_dev: uinput_sys::uinput_user_dev = unsafe { mem::zeroed() };
+        for (dst, src) in user_dev.name.iter_mut().zip(VIRTUAL_DEVICE_NAME.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        user_dev.id.bustype = 0x06; // BUS_VIRTUAL
+        user_dev.id.version = 1;
+
+        unsafe {
+            check(uinput_sys::ui_set_evbit(self.fd, EV_SYN))?;
+            for code in EMITTABLE_KEYS.iter().cloned().flatten() {
+                self.enable(EV_KEY, code)?;
+            }
+            for source in sources {
+                let abs_state = match source.supported_absolute_axes() {
+                    Some(_) => Some(source.get_abs_state()?),
+                    None => None,
+                };
+                for (kind, code) in capabilities_of(source) {
+                    self.enable(kind, code)?;
+                    if let (EV_ABS, Some(abs_state)) = (kind, abs_state.as_ref()) {
+                        let i = code as usize;
+                        user_dev.absmin[i] = abs_state[i].minimum;
+                        user_dev.absmax[i] = abs_state[i].maximum;
+                        user_dev.absfuzz[i] = abs_state[i].fuzz;
+                        user_dev.absflat[i] = abs_state[i].flat;
+                    }
+                }
+            }
+
+            let size = mem::size_of::<uinput_sys::uinput_user_dev>();
+            let ptr = &user_dev as *const uinput_sys::uinput_user_dev as *const libc::c_void;
+            if libc::write(self.fd, ptr, size) != size as isize {
+                return Err(io::Error::last_os_error());
+            }
+            check(uinput_sys::ui_dev_create(self.fd))?;
+        }
+        Ok(())
+    }
+
+    unsafe fn enable(&mut self, kind: i32, code: i32) -> io::Result<()> {
+        if !self.capabilities.iter().any(|&(k, _)| k == kind) {
+            check(uinput_sys::ui_set_evbit(self.fd, kind))?;
+        }
+        if !self.capabilities.insert((kind, code)) {
+            return Ok(());
+        }
+        check(match kind {
+            EV_KEY => uinput_sys::ui_set_keybit(self.fd, code),
+            EV_REL => uinput_sys::ui_set_relbit(self.fd, code),
+            EV_ABS => uinput_sys::ui_set_absbit(self.fd, code),
+            EV_MSC => uinput_sys::ui_set_mscbit(self.fd, code),
+            EV_LED => uinput_sys::ui_set_ledbit(self.fd, code),
+            _ => return Ok(()),
+        })
+    }
+
+    pub fn write(&mut self, kind: i32, code: i32, value: i32) -> io::Result<()> {
+        self.write_at(kind, code, value, unsafe { mem::zeroed() })
+    }
+
+    // Forward an event from the real device unchanged, timestamp included.
+    pub fn write_event(&mut self, event: &InputEvent) -> io::Result<()> {
+        if event.event_type() == EventType::SYNCHRONIZATION {
+            return self.synchronize();
+        }
+        self.write_at(
+            event.event_type().0 as i32,
+            event.code() as i32,
+            event.value(),
+            to_monotonic(event.timestamp()),
+        )
+    }
+
+    pub fn synchronize(&mut self) -> io::Result<()> {
+        self.write(EV_SYN, SYN_REPORT, 0)
+    }
+
+    pub fn release_all(&mut self) -> io::Result<()> {
+        for code in self.held_keys.drain().collect::<Vec<i32>>() {
+            println!("[output] Releasing {}", code);
+            self.write(EV_KEY, code, 0)?;
+        }
+        self.synchronize()
+    }
+
+    // A zero timestamp makes the kernel stamp the event itself.
+    fn write_at(
+        &mut self,
+        kind: i32,
+        code: i32,
+        value: i32,
+        time: libc::timeval,
+    ) -> io::Result<()> {
+        if kind == EV_KEY {
+            match value {
+                0 => self.held_keys.remove(&code),
+                _ => self.held_keys.insert(code),
+            };
+        }
+        let event = uinput_sys::input_event {
+            time,
+            kind: kind as u16,
+            code: code as u16,
+            value,
+        };
+        let size = mem::size_of::<uinput_sys::input_event>();
+        let ptr = &event as *const uinput_sys::input_event as *const libc::c_void;
+        if unsafe { libc::write(self.fd, ptr, size) } != size as isize {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VirtualKeyboard {
+    fn drop(&mut self) {
+        unsafe {
+            uinput_sys::ui_dev_destroy(self.fd);
+            libc::close(self.fd);
+        }
+    }
+}
+
+// Everything we forward from a device, as (event type, code) pairs.
+fn capabilities_of(source: &Device) -> Vec<(i32, i32)> {
+    let mut capabilities = vec![];
+    if let Some(keys) = source.supported_keys() {
+        capabilities.extend(keys.iter().map(|key| (EV_KEY, key.code() as i32)));
+    }
+    if let Some(axes) = source.supported_relative_axes() {
+        capabilities.extend(axes.iter().map(|axis| (EV_REL, axis.0 as i32)));
+    }
+    if let Some(axes) = source.supported_absolute_axes() {
+        capabilities.extend(axes.iter().map(|axis| (EV_ABS, axis.0 as i32)));
+    }
+    if let Some(misc) = source.misc_properties() {
+        capabilities.extend(misc.iter().map(|msc| (EV_MSC, msc.0 as i32)));
+    }
+    if let Some(leds) = source.supported_leds() {
+        capabilities.extend(leds.iter().map(|led| (EV_LED, led.0 as i32)));
+    }
+    capabilities
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// evdev gives us CLOCK_REALTIME stamps, but uinput (since Linux 5.4) takes
+// the injected stamp as CLOCK_MONOTONIC, so shift it by the event's age.
+// Older kernels ignore it and stamp the event on write.
+fn to_monotonic(time: SystemTime) -> libc::timeval {
+    let age = SystemTime::now().duration_since(time).unwrap_or_default();
+    let mut now: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    let now = Duration::new(now.tv_sec as u64, now.tv_nsec as u32);
+    match now.checked_sub(age) {
+        Some(stamp) => libc::timeval {
+            tv_sec: stamp.as_secs() as libc::time_t,
+            tv_usec: stamp.subsec_micros() as libc::suseconds_t,
+        },
+        None => unsafe { mem::zeroed() },
+    }
+}
@@ -0,0 +1,219 @@
+use crate::utils::config_parser::{ConfigKeyCombination, RemapSetting};
+use crate::utils::modifiers::{self, Modifiers};
+
+// A prefix trie over the `from` sequences of one setting. A single-stroke
+// `from` is just a sequence of one. Children keep config order, so the
+// first rule in the config still wins.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SequenceTrie {
+    children: Vec<(ConfigKeyCombination, SequenceTrie)>,
+    // Index into `remap` of the rule whose `from` ends here
+    pub remap: Option<usize>,
+    // Whether that rule preserves modifiers, so its last stroke matches with
+    // others held on top
+    pub preserve_modifiers: bool,
+}
+
+impl SequenceTrie {
+    pub fn build(remaps: &[RemapSetting]) -> SequenceTrie {
+        let mut trie = SequenceTrie::default();
+        for (index, remap) in remaps.iter().enumerate() {
+            let preserve = remap.preserve_modifiers == Some(true);
+            trie.insert(&remap.from, index, preserve);
+        }
+        trie
+    }
+
+    fn insert(&mut self, steps: &[ConfigKeyCombination], index: usize, preserve: bool) {
+        let (step, rest) = match steps.split_first() {
+            Some(split) => split,
+            None => {
+                if self.remap.is_none() {
+                    self.remap = Some(index);
+                    self.preserve_modifiers = preserve;
+                }
+                return;
+            }
+        };
+        let position = match self.children.iter().position(|(key, _)| key == step) {
+            Some(position) => position,
+            None => {
+                self.children.push((step.clone(), SequenceTrie::default()));
+                self.children.len() - 1
+            }
+        };
+        self.children[position].1.insert(rest, index, preserve);
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    // The child for `keyname` pressed with `held` modifiers, with its index.
+    // A rule for exactly the held modifiers wins over an earlier one that
+    // only allows them, by a wildcard or `preserve_modifiers`.
+    pub fn find_key(&self, keyname: &str, held: Modifiers) -> Option<(usize, &SequenceTrie)> {
+        let exact = |key: &ConfigKeyCombination, _: &SequenceTrie| {
+            key.keyname == keyname && modifiers::matches(&key.modifiers, Modifiers::default(), held)
+        };
+        let matches = |key: &ConfigKeyCombination, child: &SequenceTrie| {
+            let optional = match child.preserve_modifiers {
+                true => Modifiers::all(),
+                false => key.optional,
+            };
+            key.keyname == keyname && modifiers::matches(&key.modifiers, optional, held)
+        };
+        self.find(exact).or_else(|| self.find(matches))
+    }
+
+    // The first child whose key combination `matches`, with its index.
+    fn find<F>(&self, matches: F) -> Option<(usize, &SequenceTrie)>
+    where
+        F: Fn(&ConfigKeyCombination, &SequenceTrie) -> bool,
+    {
+        self.children
+            .iter()
+            .enumerate()
+            .find(|(_, (key, child))| matches(key, child))
+            .map(|(i, (_, child))| (i, child))
+    }
+
+    pub fn descend(&self, path: &[usize]) -> Option<&SequenceTrie> {
+        path.iter().try_fold(self, |node, &i| {
+            node.children.get(i).map(|(_, child)| child)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::config_parser::{parse_config, RemapTable};
+
+    fn table() -> RemapTable {
+        serde_yaml::from_str(
+            "- from: capslock.x capslock.s
+  to: [capslock.s]
+- from: capslock.x capslock.c
+  to: [capslock.q]
+- from: capslock.f
+  to: [right]
+- from: capslock.f
+  to: [end]",
+        )
+        .unwrap()
+    }
+
+    fn key(value: &'static str) -> impl Fn(&ConfigKeyCombination, &SequenceTrie) -> bool {
+        move |key, _| key.value == value
+    }
+
+    #[test]
+    fn shared_prefixes() {
+        let table = table();
+        let (x, node) = table.trie.find(key("capslock.x")).unwrap();
+        assert!(!node.is_leaf());
+        assert_eq!(node.remap, None);
+        let (s, node) = node.find(key("capslock.s")).unwrap();
+        assert!(node.is_leaf());
+        assert_eq!(node.remap, Some(0));
+        assert_eq!(table.trie.descend(&[x, s]), Some(node));
+        let (_, node) = table
+            .trie
+            .descend(&[x])
+            .unwrap()
+            .find(key("capslock.c"))
+            .unwrap();
+        assert_eq!(node.remap, Some(1));
+    }
+
+    #[test]
+    fn first_rule_wins() {
+        let table = table();
+        let (_, node) = table.trie.find(key("capslock.f")).unwrap();
+        assert_eq!(node.remap, Some(2));
+    }
+
+    #[test]
+    fn misses() {
+        let table = table();
+        assert!(table.trie.find(key("capslock.s")).is_none());
+        assert!(table.trie.descend(&[5]).is_none());
+        assert_eq!(table.trie.descend(&[]), Some(&table.trie));
+    }
+
+    #[test]
+    fn preserved_prefix() {
+        let config = parse_config(
+            "
+            preserve_modifiers: true
+            settings:
+              - applications: '*'
+                remap:
+                  - from: capslock.x
+                    to: [cut]
+                  - from: capslock.x capslock.s
+                    to: [capslock.s]
+            ",
+        )
+        .unwrap();
+        let table = &config.settings[0].remap;
+        let (x, node) = table.trie.find(key("capslock.x")).unwrap();
+        assert_eq!(node.remap, Some(0));
+        assert!(node.preserve_modifiers);
+        assert!(!node.is_leaf());
+        let (_, node) = node.find(key("capslock.s")).unwrap();
+        assert_eq!(node.remap, Some(1));
+        assert_eq!(table.trie.descend(&[x + 1]), None);
+        assert!(table.rules[0].from[0].optional.is_empty());
+    }
+
+    #[test]
+    fn exact_match_wins() {
+        let config = parse_config(
+            "
+            settings:
+              - applications: '*'
+                remap:
+                  - from: '[shift?].leftalt.f'
+                    to: [end]
+                  - from: leftalt.b
+                    to: [home]
+                    preserve_modifiers: true
+                  - from: leftshift.leftalt.f
+                    to: [capslock.end]
+                  - from: leftshift.leftalt.b
+                    to: [capslock.home]
+            ",
+        )
+        .unwrap();
+        let trie = &config.settings[0].remap.trie;
+        let find = |keyname, held: &[&str]| {
+            let held = held
+                .iter()
+                .filter_map(|name| Modifiers::of_key(name))
+                .fold(Modifiers::default(), Modifiers::union);
+            trie.find_key(keyname, held)
+        };
+        assert_eq!(find("f", &["leftalt"]).unwrap().1.remap, Some(0));
+        assert_eq!(
+            find("f", &["leftalt", "leftshift"]).unwrap().1.remap,
+            Some(2)
+        );
+        assert_eq!(
+            find("f", &["leftalt", "rightshift"]).unwrap().1.remap,
+            Some(0)
+        );
+        assert_eq!(find("b", &["leftalt"]).unwrap().1.remap, Some(1));
+        assert_eq!(
+            find("b", &["leftalt", "leftshift"]).unwrap().1.remap,
+            Some(3)
+        );
+        assert_eq!(
+            find("b", &["leftalt", "leftctrl"]).unwrap().1.remap,
+            Some(1)
+        );
+        assert!(find("f", &["leftalt", "leftctrl"]).is_none());
+        assert!(find("f", &[]).is_none());
+    }
+}
@@ -0,0 +1,106 @@
+use std::sync::{Arc, Mutex};
+
+use crate::utils::config_parser::{ConfigKeyCombination, TextFallback};
+
+// The only layout whose keys we know
+const US_LAYOUT: &str = "English (US)";
+
+// The xkb layout sway applies to our virtual keyboard, kept up to date by the
+// sway thread. None until sway has told us.
+#[derive(Clone, Default)]
+pub struct ActiveLayout(Arc<Mutex<Option<String>>>);
+
+impl ActiveLayout {
+    pub fn set(&self, name: Option<String>) {
+        let mut layout = self.0.lock().unwrap();
+        if *layout != name {
+            println!("[text] Layout {:?}", name);
+            *layout = name;
+        }
+    }
+
+    pub fn is_us(&self) -> bool {
+        self.0.lock().unwrap().as_deref() == Some(US_LAYOUT)
+    }
+}
+
+// The keys that type `text`. Printable ASCII goes out as plain key presses,
+// anything else uses `fallback`. Both need a US layout, as that's the only
+// one we know the keys of; with another, nothing is typed.
+pub fn keys(text: &str, fallback: TextFallback, is_us_layout: bool) -> Vec<ConfigKeyCombination> {
+    if !is_us_layout {
+        println!("[text] Skipping {:?}: the layout isn't {}", text, US_LAYOUT);
+        return vec![];
+    }
+    let mut keys = vec![];
+    for c in text.chars() {
+        match ascii_key(c) {
+            Some((keyname, false)) => keys.push(combination(&keyname)),
+            Some((keyname, true)) => keys.push(combination(&format!("leftshift.{}", keyname))),
+            None if fallback == TextFallback::CtrlShiftU => {
+                // GTK, Qt and IBus: ctrl+shift+u, the code point in hex, space
+                keys.push(combination("leftctrl.leftshift.u"));
+                for digit in format!("{:x}", c as u32).chars() {
+                    keys.push(combination(&digit.to_string()));
+                }
+                keys.push(combination("space"));
+            }
+            None => println!("[text] Skipping {:?}", c),
+        }
+    }
+    keys
+}
+
+fn combination(val: &str) -> ConfigKeyCombination {
+    ConfigKeyCombination::parse(val).unwrap()
+}
+
+// The key for `c` on a US layout, and whether it needs shift.
+fn ascii_key(c: char) -> Option<(String, bool)> {
+    // Letter and digit keys are named after them.
+    if c.is_ascii_lowercase() || c.is_ascii_digit() {
+        return Some((c.to_string(), false));
+    }
+    if c.is_ascii_uppercase() {
+        return Some((c.to_ascii_lowercase().to_string(), true));
+    }
+    let (keyname, shift) = match c {
+        ' ' => ("space", false),
+        '\n' => ("enter", false),
+        '\t' => ("tab", false),
+        '-' => ("minus", false),
+        '=' => ("equal", false),
+        '[' => ("leftbrace", false),
+        ']' => ("rightbrace", false),
+        '\\' => ("backslash", false),
+        ';' => ("semicolon", false),
+        '\'' => ("apostrophe", false),
+        '`' => ("grave", false),
+        ',' => ("comma", false),
+        '.' => ("dot", false),
+        '/' => ("slash", false),
+        '!' => ("1", true),
+        '@' => ("2", true),
+        '#' => ("3", true),
+        '$' => ("4", true),
+        '%' => ("5", true),
+        '^' => ("6", true),
+        '&' => ("7", true),
+        '*' => ("8", true),
+        '(' => ("9", true),
+        ')' => ("0", true),
+        '_' => ("minus", true),
+        '+' => ("equal", true),
+        '{' => ("leftbrace", true),
+        '}' => ("rightbrace", true),
+        '|' => ("backslash", true),
+        ':' => ("semicolon", true),
+        '"' => ("apostrophe", true),
+        '~' => ("grave", true),
+        '<' => ("comma", true),
+        '>' => ("dot", true),
+        '?' => ("slash", true),
+        _ => return None,
+    };
+    Some((keyname.to_string(), shift))
+}
@@ -0,0 +1,69 @@
+use std::env;
+use std::error::Error;
+use std::ffi::{CStr, CString};
+
+// When we run through sudo, the process uid is root but the sway session
+// belongs to the user who invoked sudo.
+pub fn invoking_uid() -> u32 {
+    match env::var("SUDO_UID").ok().and_then(|uid| uid.parse().ok()) {
+        Some(uid) => uid,
+        None => unsafe { libc::getuid() },
+    }
+}
+
+pub fn uid_for_name(name: &str) -> Result<u32, Box<dyn Error>> {
+    if let Ok(uid) = name.parse() {
+        return Ok(uid);
+    }
+    let c_name = CString::new(name)?;
+    let passwd = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(format!("Unknown user {}", name).into());
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+pub struct User {
+    pub uid: u32,
+    pub gid: u32,
+    pub name: CString,
+    pub home: String,
+    pub shell: String,
+    // Supplementary groups, with `gid`
+    pub groups: Vec<libc::gid_t>,
+}
+
+pub fn user_for_uid(uid: u32) -> Result<User, Box<dyn Error>> {
+    let passwd = unsafe { libc::getpwuid(uid) };
+    if passwd.is_null() {
+        return Err(format!("Unknown uid {}", uid).into());
+    }
+    let field = |ptr: *const libc::c_char| unsafe { CStr::from_ptr(ptr) };
+    let passwd = unsafe { &*passwd };
+    let name = field(passwd.pw_name).to_owned();
+    Ok(User {
+        uid,
+        gid: passwd.pw_gid,
+        groups: group_list(&name, passwd.pw_gid),
+        name,
+        home: field(passwd.pw_dir).to_string_lossy().into_owned(),
+        shell: field(passwd.pw_shell).to_string_lossy().into_owned(),
+    })
+}
+
+// The groups `name` is in, from /etc/group or NSS.
+fn group_list(name: &CStr, gid: libc::gid_t) -> Vec<libc::gid_t> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let found =
+            unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+        // Too small, `count` is how many there are.
+        if found < 0 {
+            groups.resize(count.max(groups.len() as libc::c_int * 2) as usize, 0);
+            continue;
+        }
+        groups.truncate(count as usize);
+        return groups;
+    }
+}
@@ -1,20 +1,214 @@
-use swayipc::reply;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use swayipc::reply::{self, InputChange, NodeType, WindowChange, WorkspaceChange};
+use swayipc::Connection;
 
-// TODO: not use unwrap. use result.
-pub fn get_window_class(evt: Option<Result<reply::Event, swayipc::Error>>) -> String {
+use crate::utils::output::VIRTUAL_DEVICE_NAME;
+use crate::utils::user;
+
+// Resolve the sway IPC socket the same way `swaymsg` does: explicit path,
+// then SWAYSOCK, then I3SOCK. As we usually run through sudo, those variables
+// are often stripped, so fall back to scanning /run/user for a live socket
+// owned by the invoking user (or by `sway_user` if given).
+pub fn find_sway_socket(
+    sway_socket: Option<String>,
+    sway_user: Option<String>,
+) -> Result<String, Box<dyn Error>> {
+    if let Some(path) = sway_socket {
+        return Ok(path);
+    }
+    if sway_user.is_none() {
+        for var in ["SWAYSOCK", "I3SOCK"] {
+            if let Ok(path) = env::var(var) {
+                if is_live_socket(Path::new(&path)) {
+                    return Ok(path);
+                }
+                println!("[wayland] Ignoring stale {}={}", var, path);
+            }
+        }
+    }
+    let uid = match sway_user {
+        Some(name) => user::uid_for_name(&name)?,
+        None => user::invoking_uid(),
+    };
+    scan_sway_sockets(uid)
+        .ok_or_else(|| format!("Cannot find a running sway IPC socket for uid {}", uid).into())
+}
+
+fn scan_sway_sockets(uid: u32) -> Option<String> {
+    let mut candidates: Vec<(PathBuf, i64)> = vec![];
+    for runtime_dir in fs::read_dir("/run/user").ok()?.flatten() {
+        let entries = match fs::read_dir(runtime_dir.path()) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !(name.starts_with("sway-ipc.") && name.ends_with(".sock")) {
+                continue;
+            }
+            match entry.metadata() {
+                Ok(meta) if meta.uid() == uid => candidates.push((entry.path(), meta.mtime())),
+                _ => {}
+            }
+        }
+    }
+    // A crashed sway leaves its socket behind, so prefer the newest one
+    // that still accepts connections.
+    candidates.sort_by_key(|(_, mtime)| std::cmp::Reverse(*mtime));
+    candidates
+        .into_iter()
+        .map(|(path, _)| path)
+        .find(|path| is_live_socket(path))
+        .map(|path| path.to_string_lossy().to_string())
+}
+
+fn is_live_socket(path: &Path) -> bool {
+    UnixStream::connect(path).is_ok()
+}
+
+// Settings for windows with neither an app_id nor an X11 class.
+pub const UNKNOWN_CLASS: &str = "unknown";
+
+// What has the keyboard focus.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Focus {
+    // A window, by app_id or X11 class, and its title
+    Window { class: String, title: String },
+    // Nothing, e.g. on an empty workspace
+    Nothing,
+}
+
+// The new focus, for events that move it or retitle the focused window.
+// Moves and the like leave it alone.
+pub fn get_focus_change(evt: reply::Event) -> Result<Option<Focus>, Box<dyn Error>> {
     match evt {
-        Some(Ok(reply::Event::Window(w))) => {
-            // app_id => native wayland
-            // xwayland => window_properties.class
-            match (w.container.app_id, w.container.window_properties) {
-                (Some(id), _) => id,
-                (_, Some(props)) => match props.class {
-                    Some(class) => class,
-                    _ => panic!("Cannot get window id"),
-                },
-                (_, _) => panic!("Cannot get window id"),
+        reply::Event::Window(w) => Ok(match w.change {
+            WindowChange::Focus => Some(node_focus(&w.container)),
+            WindowChange::Title if w.container.focused => Some(node_focus(&w.container)),
+            // Another window gets the focus next, with an event of its own,
+            // unless this was the last one on the workspace.
+            WindowChange::Close if w.container.focused => Some(Focus::Nothing),
+            _ => None,
+        }),
+        reply::Event::Workspace(w) => Ok(match (w.change, w.current) {
+            (WorkspaceChange::Focus, Some(current)) if is_empty(&current) => Some(Focus::Nothing),
+            _ => None,
+        }),
+        _ => Err("Unexpected sway event".into()),
+    }
+}
+
+pub fn get_focus(conn: &mut Connection) -> Result<Focus, Box<dyn Error>> {
+    let tree = conn.get_tree()?;
+    Ok(find_focused(&tree).map_or(Focus::Nothing, node_focus))
+}
+
+// The layout of our virtual keyboard, which the keys we type are read with.
+// None if sway doesn't know it yet.
+pub fn get_layout(conn: &mut Connection) -> Result<Option<String>, Box<dyn Error>> {
+    let inputs = conn.get_inputs()?;
+    Ok(inputs
+        .into_iter()
+        .find(|input| input.name == VIRTUAL_DEVICE_NAME)
+        .and_then(|input| input.xkb_active_layout_name))
+}
+
+// The new layout of our virtual keyboard, for events that set or switch it.
+pub fn get_layout_change(evt: &reply::InputEvent) -> Option<Option<String>> {
+    match evt.change {
+        InputChange::Added | InputChange::XkbKeymap | InputChange::XkbLayout
+            if evt.input.name == VIRTUAL_DEVICE_NAME =>
+        {
+            Some(evt.input.xkb_active_layout_name.clone())
+        }
+        _ => None,
+    }
+}
+
+fn find_focused(node: &reply::Node) -> Option<&reply::Node> {
+    if node.focused {
+        return Some(node);
+    }
+    node.nodes
+        .iter()
+        .chain(node.floating_nodes.iter())
+        .find_map(find_focused)
+}
+
+fn is_empty(workspace: &reply::Node) -> bool {
+    workspace.nodes.is_empty() && workspace.floating_nodes.is_empty()
+}
+
+fn node_focus(node: &reply::Node) -> Focus {
+    if !matches!(node.node_type, NodeType::Con | NodeType::FloatingCon) {
+        return Focus::Nothing;
+    }
+    // app_id => native wayland
+    // xwayland => window_properties.class
+    let class = match (&node.app_id, &node.window_properties) {
+        (Some(id), _) => Some(id.clone()),
+        (_, Some(props)) => props.class.clone(),
+        (_, _) => None,
+    };
+    Focus::Window {
+        class: class.unwrap_or_else(|| UNKNOWN_CLASS.to_string()),
+        title: node.name.clone().unwrap_or_default(),
+    }
+}
+
+// Runs sway commands for macros. The connection is opened on the socket sway
+// currently listens on, and opened again once it fails, as after a restart.
+pub struct SwayCommands {
+    conn: Option<Connection>,
+    sway_socket: Arc<Mutex<String>>,
+}
+
+impl SwayCommands {
+    pub fn new(sway_socket: Arc<Mutex<String>>) -> SwayCommands {
+        SwayCommands {
+            conn: None,
+            sway_socket,
+        }
+    }
+
+    // Failures are only reported; a bad command shouldn't stop the macro.
+    pub fn run(&mut self, command: &str) {
+        println!("[sway] {}", command);
+        // Tried twice, the second time on a fresh connection.
+        for _ in 0..2 {
+            let conn = match self.conn.as_mut() {
+                Some(conn) => conn,
+                None => {
+                    let sway_socket = self.sway_socket.lock().unwrap().clone();
+                    match Connection::new(Some(sway_socket)) {
+                        Ok(conn) => self.conn.insert(conn),
+                        Err(e) => {
+                            println!("[sway] Failed to connect to sway: {}", e);
+                            return;
+                        }
+                    }
+                }
+            };
+            match conn.run_command(command) {
+                Ok(outcomes) => {
+                    for outcome in outcomes {
+                        if let Err(e) = outcome {
+                            println!("[sway] {} failed: {}", command, e);
+                        }
+                    }
+                    return;
+                }
+                Err(e) => {
+                    println!("[sway] Failed to send {}: {}", command, e);
+                    self.conn = None;
+                }
             }
         }
-        _ => panic!("Cannot get window id"),
     }
 }